@@ -1,6 +1,8 @@
 bits 64
 
 extern kernel_main2
+extern exception_handler
+extern interrupt_dispatch
 
 section .bss align=16
 kernel_main_stack:
@@ -10,6 +12,7 @@ section .text
 global kernel_main
 kernel_main:
   mov rsp, kernel_main_stack + 1024 * 1024
+  xor rbp, rbp ; terminates the frame pointer chain for backtraces
   call kernel_main2
 .fin:
   hlt
@@ -49,7 +52,120 @@ set_ds_all:
   mov gs, di
   ret
 
+global load_tr ; load_tr(selector: u16)
+load_tr:
+  ltr di
+  ret
+
 global set_cr3 ; set_cr3(address: u64)
 set_cr3:
   mov cr3, rdi
   ret
+
+; Exceptions which push an error code onto the stack.
+%define EXCEPTION_HAS_ERROR_CODE(v) ((v) == 8 || (v) == 10 || (v) == 11 || (v) == 12 || (v) == 13 || (v) == 14 || (v) == 17 || (v) == 21 || (v) == 29 || (v) == 30)
+
+; Every stub pushes (error code, vector) so that the stack always has the same
+; layout as ExceptionContext in interrupt.rs when exception_common is reached.
+%assign vector 0
+%rep 32
+exception_stub_%[vector]:
+%if !EXCEPTION_HAS_ERROR_CODE(vector)
+  push 0
+%endif
+  push vector
+  jmp exception_common
+%assign vector vector + 1
+%endrep
+
+exception_common:
+  push rax
+  push rbx
+  push rcx
+  push rdx
+  push rsi
+  push rdi
+  push rbp
+  push r8
+  push r9
+  push r10
+  push r11
+  push r12
+  push r13
+  push r14
+  push r15
+  mov rdi, rsp
+  cld
+  call exception_handler ; exception_handler(context: *mut ExceptionContext)
+  pop r15
+  pop r14
+  pop r13
+  pop r12
+  pop r11
+  pop r10
+  pop r9
+  pop r8
+  pop rbp
+  pop rdi
+  pop rsi
+  pop rdx
+  pop rcx
+  pop rbx
+  pop rax
+  add rsp, 16 ; vector, error code
+  iretq
+
+; Device interrupts (vector 32-255) are dispatched to the handlers registered
+; through interrupt::register_handler.
+%assign vector 32
+%rep 256 - 32
+interrupt_stub_%[vector]:
+  push vector
+  jmp interrupt_common
+%assign vector vector + 1
+%endrep
+
+interrupt_common:
+  ; Save caller-saved registers. interrupt_dispatch preserves the rest.
+  push rax
+  push rcx
+  push rdx
+  push rsi
+  push rdi
+  push r8
+  push r9
+  push r10
+  push r11
+  mov rdi, [rsp + 9 * 8] ; vector
+  sub rsp, 8 ; align the stack to 16 bytes
+  cld
+  call interrupt_dispatch ; interrupt_dispatch(vector: u64)
+  add rsp, 8
+  pop r11
+  pop r10
+  pop r9
+  pop r8
+  pop rdi
+  pop rsi
+  pop rdx
+  pop rcx
+  pop rax
+  add rsp, 8 ; vector
+  iretq
+
+section .rodata
+global exception_stub_table ; exception_stub_table: [u64; 32]
+exception_stub_table:
+%assign vector 0
+%rep 32
+  dq exception_stub_%[vector]
+%assign vector vector + 1
+%endrep
+
+global interrupt_stub_table ; interrupt_stub_table: [u64; 256 - 32]
+interrupt_stub_table:
+%assign vector 32
+%rep 256 - 32
+  dq interrupt_stub_%[vector]
+%assign vector vector + 1
+%endrep
@@ -1,7 +1,105 @@
 use std::env;
-use std::path::PathBuf;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
+const PCI_IDS: &str = "data/pci.ids";
+
+fn parse_id(text: &str) -> (u16, String) {
+    let (id, name) = text
+        .split_once("  ")
+        .unwrap_or_else(|| panic!("{}: malformed line: {:?}", PCI_IDS, text));
+    (
+        u16::from_str_radix(id.trim(), 16).unwrap(),
+        name.trim().to_string(),
+    )
+}
+
+// Turns data/pci.ids into sorted static tables for src/pci_ids.rs.
+fn generate_pci_ids(out_dir: &Path) {
+    println!("cargo:rerun-if-changed={}", PCI_IDS);
+    let source = fs::read_to_string(PCI_IDS).unwrap();
+
+    // (id, name, children) with devices or subclasses with their prog-ifs
+    let mut vendors: Vec<(u16, String, Vec<(u16, String)>)> = Vec::new();
+    let mut classes: Vec<(u16, String, Vec<(u16, String, Vec<(u16, String)>)>)> = Vec::new();
+    let mut in_class = false;
+    for line in source.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(class) = line.strip_prefix("C ") {
+            let (id, name) = parse_id(class);
+            classes.push((id, name, Vec::new()));
+            in_class = true;
+        } else if let Some(item) = line.strip_prefix("\t\t") {
+            // Subsystems under devices are not used
+            if in_class {
+                let subclass = classes.last_mut().unwrap().2.last_mut().unwrap();
+                subclass.2.push(parse_id(item));
+            }
+        } else if let Some(item) = line.strip_prefix('\t') {
+            let (id, name) = parse_id(item);
+            if in_class {
+                classes.last_mut().unwrap().2.push((id, name, Vec::new()));
+            } else {
+                vendors.last_mut().unwrap().2.push((id, name));
+            }
+        } else {
+            let (id, name) = parse_id(line);
+            vendors.push((id, name, Vec::new()));
+            in_class = false;
+        }
+    }
+
+    vendors.sort_by_key(|vendor| vendor.0);
+    classes.sort_by_key(|class| class.0);
+    let mut code = String::new();
+    writeln!(code, "pub static VENDORS: &[Vendor] = &[").unwrap();
+    for (id, name, mut devices) in vendors {
+        devices.sort_by_key(|device| device.0);
+        write!(
+            code,
+            "Vendor {{ id: {:#06x}, name: {:?}, devices: &[",
+            id, name
+        )
+        .unwrap();
+        for (id, name) in devices {
+            write!(code, "({:#06x}, {:?}), ", id, name).unwrap();
+        }
+        writeln!(code, "] }},").unwrap();
+    }
+    writeln!(code, "];").unwrap();
+    writeln!(code, "pub static CLASSES: &[Class] = &[").unwrap();
+    for (id, name, mut subclasses) in classes {
+        subclasses.sort_by_key(|subclass| subclass.0);
+        write!(
+            code,
+            "Class {{ id: {:#04x}, name: {:?}, subclasses: &[",
+            id, name
+        )
+        .unwrap();
+        for (id, name, mut prog_ifs) in subclasses {
+            prog_ifs.sort_by_key(|prog_if| prog_if.0);
+            write!(
+                code,
+                "SubClass {{ id: {:#04x}, name: {:?}, prog_ifs: &[",
+                id, name
+            )
+            .unwrap();
+            for (id, name) in prog_ifs {
+                write!(code, "({:#04x}, {:?}), ", id, name).unwrap();
+            }
+            write!(code, "] }}, ").unwrap();
+        }
+        writeln!(code, "] }},").unwrap();
+    }
+    writeln!(code, "];").unwrap();
+
+    fs::write(out_dir.join("pci_ids.rs"), code).unwrap();
+}
+
 fn main() {
     let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
     println!("cargo:rustc-link-search={}", out_dir.display());
@@ -22,4 +120,6 @@ fn main() {
         .status()
         .unwrap();
     println!("cargo:rustc-link-lib=static=asm");
+
+    generate_pci_ids(&out_dir);
 }
@@ -0,0 +1,38 @@
+#!/bin/sh
+# Boots a kernel test binary with the MikanOS loader in QEMU. Used as the cargo
+# runner, so `cargo test` gets the path of the test binary as $1.
+#
+# The loader and OVMF are taken from the MikanOS development environment
+# (https://github.com/uchan-nos/mikanos-build). Override with LOADER_EFI and DEVENV_DIR.
+set -eu
+
+DEVENV_DIR=${DEVENV_DIR:-$HOME/osbook/devenv}
+LOADER_EFI=${LOADER_EFI:-$HOME/edk2/Build/MikanLoaderX64/DEBUG_CLANG38/X64/Loader.efi}
+TEST_TIMEOUT=${TEST_TIMEOUT:-120}
+
+WORK_DIR=$(mktemp -d)
+trap 'rm -rf "$WORK_DIR"' EXIT
+
+# The loader always loads \kernel.elf
+cp "$1" "$WORK_DIR/kernel.elf"
+cp "$DEVENV_DIR/OVMF_VARS.fd" "$WORK_DIR/OVMF_VARS.fd"
+"$DEVENV_DIR/make_image.sh" "$WORK_DIR/disk.img" "$WORK_DIR/mnt" "$LOADER_EFI" "$WORK_DIR/kernel.elf"
+
+# Passing runs power off (exit status 0). A panic writes to isa-debug-exit (status 3).
+status=0
+timeout "$TEST_TIMEOUT" qemu-system-x86_64 \
+    -m 1G \
+    -drive if=pflash,format=raw,readonly=on,file="$DEVENV_DIR/OVMF_CODE.fd" \
+    -drive if=pflash,format=raw,file="$WORK_DIR/OVMF_VARS.fd" \
+    -drive if=ide,index=0,media=disk,format=raw,file="$WORK_DIR/disk.img" \
+    -device nec-usb-xhci,id=xhci \
+    -device usb-mouse -device usb-kbd \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
+    ${QEMU_OPTS:-} || status=$?
+
+case $status in
+    0) ;;
+    124) echo "Tests timed out after ${TEST_TIMEOUT}s" >&2 ;;
+    *) echo "Tests failed (QEMU exit status $status)" >&2 ;;
+esac
+exit $status
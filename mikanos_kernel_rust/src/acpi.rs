@@ -0,0 +1,612 @@
+use crate::ioapic::{Polarity, TriggerMode};
+use crate::log;
+use crate::logger::Level as LogLevel;
+use crate::paging::as_virt_addr;
+use crate::sync::once_cell::OnceCell;
+use alloc::vec::Vec;
+use bit_field::BitField;
+use core::convert::{TryFrom, TryInto};
+use core::ffi::c_void;
+use core::{fmt, slice, str};
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// Length of the ACPI 1.0 part of the RSDP covered by `checksum`
+const RSDP_V1_LENGTH: usize = 20;
+const RSDP_V2_LENGTH: usize = 36;
+const DESCRIPTION_HEADER_LENGTH: usize = 36;
+
+// ACPI PM timer frequency
+pub const PM_TIMER_FREQUENCY: u64 = 3_579_545;
+
+static TABLES: OnceCell<AcpiTables> = OnceCell::uninit();
+
+/// Parsed ACPI tables, or None if the loader did not pass an RSDP.
+pub fn tables() -> Option<&'static AcpiTables> {
+    TABLES.try_get().ok()
+}
+
+fn read_u8(bytes: &[u8], offset: usize) -> Option<u8> {
+    bytes.get(offset).copied()
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn sum_is_zero(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+unsafe fn physical_bytes(addr: u64, len: usize) -> Result<&'static [u8], ()> {
+    let virt_addr = as_virt_addr(PhysAddr::new(addr)).ok_or(())?;
+    Ok(slice::from_raw_parts(virt_addr.as_ptr::<u8>(), len))
+}
+
+/// A system description table with a valid checksum.
+#[derive(Clone, Copy)]
+struct Table {
+    bytes: &'static [u8],
+}
+
+impl Table {
+    unsafe fn from_physical_address(addr: u64) -> Result<Self, ()> {
+        let header = physical_bytes(addr, DESCRIPTION_HEADER_LENGTH)?;
+        let length = read_u32(header, 4).ok_or(())? as usize;
+        if length < DESCRIPTION_HEADER_LENGTH {
+            return Err(());
+        }
+        let bytes = physical_bytes(addr, length)?;
+        if !sum_is_zero(bytes) {
+            log!(
+                LogLevel::Warn,
+                "ACPI: checksum error in {}\n",
+                str::from_utf8(&bytes[0..4]).unwrap_or("????")
+            );
+            return Err(());
+        }
+        Ok(Self { bytes })
+    }
+
+    fn signature(&self) -> &[u8] {
+        &self.bytes[0..4]
+    }
+
+    fn revision(&self) -> u8 {
+        self.bytes[8]
+    }
+
+    fn body(&self) -> &'static [u8] {
+        &self.bytes[DESCRIPTION_HEADER_LENGTH..]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfiguration,
+    Other(u8),
+}
+
+impl From<u8> for AddressSpace {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::SystemMemory,
+            1 => Self::SystemIo,
+            2 => Self::PciConfiguration,
+            _ => Self::Other(value),
+        }
+    }
+}
+
+/// Generic Address Structure
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub fn write_u8(&self, value: u8) -> Result<(), ()> {
+        match self.address_space {
+            AddressSpace::SystemIo => {
+                let port = u16::try_from(self.address).map_err(|_| ())?;
+                unsafe { Port::<u8>::new(port).write(value) };
+            }
+            AddressSpace::SystemMemory => {
+                let virt_addr = as_virt_addr(PhysAddr::new(self.address)).ok_or(())?;
+                unsafe { core::ptr::write_volatile(virt_addr.as_mut_ptr::<u8>(), value) };
+            }
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    fn parse(bytes: &[u8], offset: usize) -> Option<Self> {
+        Some(Self {
+            address_space: read_u8(bytes, offset)?.into(),
+            bit_width: read_u8(bytes, offset + 1)?,
+            bit_offset: read_u8(bytes, offset + 2)?,
+            access_size: read_u8(bytes, offset + 3)?,
+            address: read_u64(bytes, offset + 4)?,
+        })
+    }
+}
+
+/// Fixed ACPI Description Table
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub century: u8,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+    pub dsdt_address: u64,
+}
+
+impl Fadt {
+    const FLAG_TMR_VAL_EXT: usize = 8;
+    const FLAG_RESET_REG_SUP: usize = 10;
+
+    fn parse(table: &Table) -> Result<Self, ()> {
+        let bytes = table.bytes;
+        let dsdt = read_u32(bytes, 40).ok_or(())?;
+        // X_DSDT takes precedence when present
+        let x_dsdt = read_u64(bytes, 140).unwrap_or(0);
+        Ok(Self {
+            sci_interrupt: read_u16(bytes, 46).ok_or(())?,
+            smi_command_port: read_u32(bytes, 48).ok_or(())?,
+            acpi_enable: read_u8(bytes, 52).ok_or(())?,
+            acpi_disable: read_u8(bytes, 53).ok_or(())?,
+            pm1a_control_block: read_u32(bytes, 64).ok_or(())?,
+            pm1b_control_block: read_u32(bytes, 68).ok_or(())?,
+            pm_timer_block: read_u32(bytes, 76).ok_or(())?,
+            century: read_u8(bytes, 108).unwrap_or(0),
+            flags: read_u32(bytes, 112).unwrap_or(0),
+            reset_register: GenericAddress::parse(bytes, 116),
+            reset_value: read_u8(bytes, 128).unwrap_or(0),
+            dsdt_address: if x_dsdt != 0 { x_dsdt } else { u64::from(dsdt) },
+        })
+    }
+
+    /// Whether the PM timer counts with 32 bits instead of 24 bits.
+    pub fn pm_timer_is_32bit(&self) -> bool {
+        self.flags.get_bit(Self::FLAG_TMR_VAL_EXT)
+    }
+
+    pub fn reset_register_supported(&self) -> bool {
+        self.flags.get_bit(Self::FLAG_RESET_REG_SUP) && self.reset_register.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicEntry {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// Maps an ISA IRQ to a global system interrupt. None means "conforms to the bus".
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub polarity: Option<Polarity>,
+    pub trigger_mode: Option<TriggerMode>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    // 0xff means all processors
+    pub processor_id: u8,
+    pub polarity: Option<Polarity>,
+    pub trigger_mode: Option<TriggerMode>,
+    pub lint: u8,
+}
+
+fn parse_mps_inti_flags(flags: u16) -> (Option<Polarity>, Option<TriggerMode>) {
+    let polarity = match flags.get_bits(0..2) {
+        0b01 => Some(Polarity::ActiveHigh),
+        0b11 => Some(Polarity::ActiveLow),
+        _ => None,
+    };
+    let trigger_mode = match flags.get_bits(2..4) {
+        0b01 => Some(TriggerMode::Edge),
+        0b11 => Some(TriggerMode::Level),
+        _ => None,
+    };
+    (polarity, trigger_mode)
+}
+
+/// Multiple APIC Description Table
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub pcat_compatible: bool,
+    pub local_apics: Vec<LocalApicEntry>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub interrupt_source_overrides: Vec<InterruptSourceOverride>,
+    pub local_apic_nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    const ENTRY_LOCAL_APIC: u8 = 0;
+    const ENTRY_IO_APIC: u8 = 1;
+    const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+    const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+    const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+    fn parse(table: &Table) -> Result<Self, ()> {
+        let body = table.body();
+        let mut madt = Self {
+            local_apic_address: u64::from(read_u32(body, 0).ok_or(())?),
+            pcat_compatible: read_u32(body, 4).ok_or(())?.get_bit(0),
+            local_apics: Vec::new(),
+            io_apics: Vec::new(),
+            interrupt_source_overrides: Vec::new(),
+            local_apic_nmis: Vec::new(),
+        };
+
+        let mut offset = 8;
+        while offset + 2 <= body.len() {
+            let entry_type = body[offset];
+            let length = body[offset + 1] as usize;
+            if length < 2 || offset + length > body.len() {
+                return Err(());
+            }
+            let entry = &body[offset..offset + length];
+            match entry_type {
+                Self::ENTRY_LOCAL_APIC => madt.local_apics.push(LocalApicEntry {
+                    processor_id: read_u8(entry, 2).ok_or(())?,
+                    apic_id: read_u8(entry, 3).ok_or(())?,
+                    enabled: read_u32(entry, 4).ok_or(())?.get_bit(0),
+                }),
+                Self::ENTRY_IO_APIC => madt.io_apics.push(IoApicEntry {
+                    id: read_u8(entry, 2).ok_or(())?,
+                    address: read_u32(entry, 4).ok_or(())?,
+                    gsi_base: read_u32(entry, 8).ok_or(())?,
+                }),
+                Self::ENTRY_INTERRUPT_SOURCE_OVERRIDE => {
+                    let (polarity, trigger_mode) =
+                        parse_mps_inti_flags(read_u16(entry, 8).ok_or(())?);
+                    madt.interrupt_source_overrides
+                        .push(InterruptSourceOverride {
+                            bus: read_u8(entry, 2).ok_or(())?,
+                            source: read_u8(entry, 3).ok_or(())?,
+                            gsi: read_u32(entry, 4).ok_or(())?,
+                            polarity,
+                            trigger_mode,
+                        });
+                }
+                Self::ENTRY_LOCAL_APIC_NMI => {
+                    let (polarity, trigger_mode) =
+                        parse_mps_inti_flags(read_u16(entry, 3).ok_or(())?);
+                    madt.local_apic_nmis.push(LocalApicNmi {
+                        processor_id: read_u8(entry, 2).ok_or(())?,
+                        polarity,
+                        trigger_mode,
+                        lint: read_u8(entry, 5).ok_or(())?,
+                    });
+                }
+                Self::ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                    madt.local_apic_address = read_u64(entry, 4).ok_or(())?;
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+
+        Ok(madt)
+    }
+
+    /// Returns the override for an ISA IRQ, if the firmware wired it differently.
+    pub fn interrupt_source_override(&self, irq: u8) -> Option<&InterruptSourceOverride> {
+        self.interrupt_source_overrides
+            .iter()
+            .find(|iso| iso.bus == 0 && iso.source == irq)
+    }
+}
+
+/// High Precision Event Timer Table
+#[derive(Debug, Clone, Copy)]
+pub struct HpetInfo {
+    pub base_address: u64,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+}
+
+impl HpetInfo {
+    fn parse(table: &Table) -> Result<Self, ()> {
+        let body = table.body();
+        let base = GenericAddress::parse(body, 4).ok_or(())?;
+        if base.address_space != AddressSpace::SystemMemory {
+            return Err(());
+        }
+        Ok(Self {
+            base_address: base.address,
+            hpet_number: read_u8(body, 16).ok_or(())?,
+            minimum_tick: read_u16(body, 17).ok_or(())?,
+        })
+    }
+}
+
+/// An ECAM region of the PCI Express Memory Mapped Configuration Space Base Address
+/// Description Table. `base_address` corresponds to bus 0 even if `start_bus` is not 0.
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+fn parse_mcfg(table: &Table) -> Vec<McfgEntry> {
+    // The entries follow 8 reserved bytes
+    table
+        .body()
+        .get(8..)
+        .unwrap_or(&[])
+        .chunks_exact(16)
+        .map(|entry| McfgEntry {
+            base_address: read_u64(entry, 0).unwrap(),
+            segment: read_u16(entry, 8).unwrap(),
+            start_bus: entry[10],
+            end_bus: entry[11],
+        })
+        .collect()
+}
+
+/// SLP_TYPx values of a sleep state, written to PM1a_CNT and PM1b_CNT.
+#[derive(Debug, Clone, Copy)]
+pub struct SleepType {
+    pub pm1a: u16,
+    pub pm1b: u16,
+}
+
+// AML encodings needed to read the \_S5 package
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_WORD_PREFIX: u8 = 0x0b;
+const AML_DWORD_PREFIX: u8 = 0x0c;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ROOT_CHAR: u8 = b'\\';
+
+fn parse_aml_integer(aml: &[u8], offset: &mut usize) -> Option<u64> {
+    let op = read_u8(aml, *offset)?;
+    *offset += 1;
+    let (value, size) = match op {
+        AML_ZERO_OP => (0, 0),
+        AML_ONE_OP => (1, 0),
+        AML_BYTE_PREFIX => (u64::from(read_u8(aml, *offset)?), 1),
+        AML_WORD_PREFIX => (u64::from(read_u16(aml, *offset)?), 2),
+        AML_DWORD_PREFIX => (u64::from(read_u32(aml, *offset)?), 4),
+        _ => return None,
+    };
+    *offset += size;
+    Some(value)
+}
+
+/// Finds `Name (\_S5, Package () { SLP_TYPa, SLP_TYPb, ... })` without interpreting AML.
+fn parse_s5(aml: &[u8]) -> Option<SleepType> {
+    aml.windows(4)
+        .enumerate()
+        .filter(|(_, name)| *name == b"_S5_")
+        .find_map(|(pos, _)| {
+            let is_name = match pos {
+                0 => false,
+                1 => aml[0] == AML_NAME_OP,
+                _ => {
+                    aml[pos - 1] == AML_NAME_OP
+                        || (aml[pos - 1] == AML_ROOT_CHAR && aml[pos - 2] == AML_NAME_OP)
+                }
+            };
+            if !is_name {
+                return None;
+            }
+            let mut offset = pos + 4;
+            if read_u8(aml, offset)? != AML_PACKAGE_OP {
+                return None;
+            }
+            // PkgLength: bits 6-7 of the lead byte count the bytes that follow it
+            offset += 1;
+            offset += 1 + usize::from(read_u8(aml, offset)? >> 6);
+            // NumElements
+            offset += 1;
+            let pm1a = parse_aml_integer(aml, &mut offset)?;
+            let pm1b = parse_aml_integer(aml, &mut offset)?;
+            Some(SleepType {
+                pm1a: pm1a as u16,
+                pm1b: pm1b as u16,
+            })
+        })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signature([u8; 4]);
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", str::from_utf8(&self.0).unwrap_or("????"))
+    }
+}
+
+#[derive(Debug)]
+pub struct AcpiTables {
+    pub revision: u8,
+    pub signatures: Vec<Signature>,
+    pub fadt: Fadt,
+    pub madt: Option<Madt>,
+    pub hpet: Option<HpetInfo>,
+    pub mcfg: Vec<McfgEntry>,
+    pub s5_sleep_type: Option<SleepType>,
+}
+
+impl AcpiTables {
+    /// Busy-waits using the ACPI PM timer. Returns Err if the firmware has none.
+    pub fn pm_timer_wait_milliseconds(&self, milliseconds: u64) -> Result<(), ()> {
+        let port = self.fadt.pm_timer_block;
+        if port == 0 || port > u32::from(u16::MAX) {
+            return Err(());
+        }
+        let mut pm_timer = Port::<u32>::new(port as u16);
+        let mask: u64 = if self.fadt.pm_timer_is_32bit() {
+            0xffff_ffff
+        } else {
+            0x00ff_ffff
+        };
+
+        let count = PM_TIMER_FREQUENCY * milliseconds / 1000;
+        let mut last = u64::from(unsafe { pm_timer.read() });
+        let mut elapsed = 0;
+        while elapsed < count {
+            let now = u64::from(unsafe { pm_timer.read() });
+            // The counter wraps around at the mask
+            elapsed += now.wrapping_sub(last) & mask;
+            last = now;
+        }
+        Ok(())
+    }
+}
+
+fn parse_rsdp(rsdp_addr: u64) -> Result<(u8, Vec<u64>), ()> {
+    let rsdp = unsafe { physical_bytes(rsdp_addr, RSDP_V2_LENGTH)? };
+    if &rsdp[0..8] != RSDP_SIGNATURE || !sum_is_zero(&rsdp[..RSDP_V1_LENGTH]) {
+        log!(LogLevel::Error, "ACPI: invalid RSDP\n");
+        return Err(());
+    }
+    let revision = rsdp[15];
+
+    // ACPI 2.0+ has the XSDT with 64 bit entries. ACPI 1.0 only has the RSDT.
+    let (root, entry_size) = if revision >= 2 {
+        if !sum_is_zero(&rsdp[..RSDP_V2_LENGTH]) {
+            log!(LogLevel::Error, "ACPI: invalid extended RSDP checksum\n");
+            return Err(());
+        }
+        (read_u64(rsdp, 24).ok_or(())?, 8)
+    } else {
+        (u64::from(read_u32(rsdp, 16).ok_or(())?), 4)
+    };
+
+    let root = unsafe { Table::from_physical_address(root)? };
+    let expected_signature: &[u8] = if entry_size == 8 { b"XSDT" } else { b"RSDT" };
+    if root.signature() != expected_signature {
+        return Err(());
+    }
+    let entries = root
+        .body()
+        .chunks_exact(entry_size)
+        .map(|entry| {
+            if entry_size == 8 {
+                read_u64(entry, 0).unwrap()
+            } else {
+                u64::from(read_u32(entry, 0).unwrap())
+            }
+        })
+        .collect();
+
+    Ok((revision, entries))
+}
+
+pub fn init(rsdp: *const c_void) -> Result<(), ()> {
+    if rsdp.is_null() {
+        log!(LogLevel::Warn, "ACPI: RSDP is not passed by the loader\n");
+        return Err(());
+    }
+    let (revision, entries) = parse_rsdp(rsdp as u64)?;
+
+    let mut signatures = Vec::new();
+    let mut fadt = None;
+    let mut madt = None;
+    let mut hpet = None;
+    let mut mcfg = Vec::new();
+    for addr in entries {
+        let table = match unsafe { Table::from_physical_address(addr) } {
+            Ok(table) => table,
+            Err(_) => continue,
+        };
+        signatures.push(Signature(table.signature().try_into().unwrap()));
+        match table.signature() {
+            b"FACP" => fadt = Some(Fadt::parse(&table)?),
+            b"APIC" => madt = Some(Madt::parse(&table)?),
+            b"HPET" => hpet = HpetInfo::parse(&table).ok(),
+            b"MCFG" => mcfg = parse_mcfg(&table),
+            _ => {}
+        }
+        log!(
+            LogLevel::Debug,
+            "ACPI: {:?} at {:08x}, revision {}\n",
+            signatures.last().unwrap(),
+            addr,
+            table.revision()
+        );
+    }
+
+    let fadt = match fadt {
+        Some(fadt) => fadt,
+        None => {
+            log!(LogLevel::Error, "ACPI: FADT is not found\n");
+            return Err(());
+        }
+    };
+    let s5_sleep_type = match fadt.dsdt_address {
+        0 => None,
+        dsdt => unsafe { Table::from_physical_address(dsdt) }
+            .ok()
+            .and_then(|dsdt| parse_s5(dsdt.body())),
+    };
+    if s5_sleep_type.is_none() {
+        log!(LogLevel::Warn, "ACPI: \\_S5 is not found in the DSDT\n");
+    }
+    log!(
+        LogLevel::Info,
+        "ACPI {}: {:?}\n",
+        if revision >= 2 { "2.0+" } else { "1.0" },
+        signatures
+    );
+
+    TABLES.init_once(|| AcpiTables {
+        revision,
+        signatures,
+        fadt,
+        madt,
+        hpet,
+        mcfg,
+        s5_sleep_type,
+    });
+    Ok(())
+}
@@ -1,12 +1,29 @@
+#[cfg(feature = "heap-debug")]
+use crate::heap_debug;
 use crate::log;
 use crate::logger::Level as LogLevel;
 use crate::memory_manager::{memory_manager, FrameId};
 use crate::paging::{as_phys_addr, as_virt_addr};
+use crate::slab::{SlabCache, SlabStats};
+use arrayvec::ArrayVec;
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 use spin::mutex::SpinMutex;
 
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+const BLOCK_SIZE_COUNT: usize = BLOCK_SIZES.len();
+// Tells the caches apart in the stats
+const BLOCK_CACHE_NAMES: [&str; BLOCK_SIZE_COUNT] = [
+    "block-8",
+    "block-16",
+    "block-32",
+    "block-64",
+    "block-128",
+    "block-256",
+    "block-512",
+    "block-1024",
+    "block-2048",
+];
 
 enum AllocationMode {
     Block(usize),
@@ -24,53 +41,46 @@ impl From<Layout> for AllocationMode {
 }
 
 pub struct KernelAllocator {
-    blocks: SpinMutex<[*mut u8; BLOCK_SIZES.len()]>,
+    blocks: [SpinMutex<SlabCache>; BLOCK_SIZE_COUNT],
 }
 
-fn allocate_frame_for_block(index: usize) -> *mut u8 {
-    let block_size = BLOCK_SIZES[index];
-    let num_block_per_frame = FrameId::SIZE / block_size;
-    let ptr: *mut u8 = match memory_manager().allocate(1) {
-        Ok(frame) => as_virt_addr(frame.to_physical_address())
-            .unwrap()
-            .as_mut_ptr(),
-        Err(_) => return ptr::null_mut(),
-    };
-    for i in 0..num_block_per_frame {
-        let current = unsafe { ptr.add(i * block_size) };
-        let next = if i == num_block_per_frame - 1 {
-            ptr::null_mut()
-        } else {
-            unsafe { current.add(block_size) }
-        };
-        unsafe { (current as *mut u64).write(next as u64) };
-    }
-    ptr
+const fn block_cache(index: usize) -> SpinMutex<SlabCache> {
+    SpinMutex::new(SlabCache::new(
+        BLOCK_CACHE_NAMES[index],
+        BLOCK_SIZES[index],
+        BLOCK_SIZES[index],
+    ))
 }
 
 impl KernelAllocator {
     pub const fn new() -> Self {
         Self {
-            blocks: SpinMutex::new([ptr::null_mut(); BLOCK_SIZES.len()]),
+            blocks: [
+                block_cache(0),
+                block_cache(1),
+                block_cache(2),
+                block_cache(3),
+                block_cache(4),
+                block_cache(5),
+                block_cache(6),
+                block_cache(7),
+                block_cache(8),
+            ],
         }
     }
-}
 
-unsafe impl Sync for KernelAllocator {}
+    pub fn stats(&self) -> ArrayVec<SlabStats, BLOCK_SIZE_COUNT> {
+        self.blocks
+            .iter()
+            .map(|cache| cache.lock().stats())
+            .collect()
+    }
 
-unsafe impl GlobalAlloc for KernelAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
         match layout.into() {
             // 2048以下
             AllocationMode::Block(index) => {
-                let mut blocks = self.blocks.lock();
-                let mut ptr = blocks[index];
-                if ptr.is_null() {
-                    ptr = allocate_frame_for_block(index);
-                }
-                if !ptr.is_null() {
-                    blocks[index] = (ptr as *const u64).read() as *mut u8;
-                }
+                let ptr = self.blocks[index].lock().allocate();
                 log!(
                     LogLevel::Trace,
                     "allocator: allocator block (size = {}) -> {:?}\n",
@@ -97,7 +107,7 @@ unsafe impl GlobalAlloc for KernelAllocator {
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
         match layout.into() {
             AllocationMode::Block(index) => {
                 log!(
@@ -106,10 +116,7 @@ unsafe impl GlobalAlloc for KernelAllocator {
                     BLOCK_SIZES[index],
                     x86_64::VirtAddr::from_ptr(ptr)
                 );
-                let mut blocks = self.blocks.lock();
-                let next = blocks[index];
-                (ptr as *mut u64).write(next as u64);
-                blocks[index] = ptr;
+                self.blocks[index].lock().free(ptr);
             }
             AllocationMode::Frame(num) => {
                 let addr = x86_64::VirtAddr::from_ptr(ptr as *const u8);
@@ -126,10 +133,59 @@ unsafe impl GlobalAlloc for KernelAllocator {
     }
 }
 
+#[cfg(not(feature = "heap-debug"))]
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.deallocate(ptr, layout)
+    }
+}
+
+// Every block gets a header and red zones, checked when it is freed
+#[cfg(feature = "heap-debug")]
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        heap_debug::on_alloc(self.allocate(heap_debug::padded_layout(layout)), layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let block = heap_debug::on_dealloc(ptr, layout);
+        self.deallocate(block, heap_debug::padded_layout(layout))
+    }
+}
+
 #[global_allocator]
 static ALLOCATOR: KernelAllocator = KernelAllocator::new();
 
+/// Counters of the block size classes.
+pub fn stats() -> ArrayVec<SlabStats, BLOCK_SIZE_COUNT> {
+    ALLOCATOR.stats()
+}
+
+/// Logs the counters of the block size classes. Only visible at the debug level.
+pub fn print_stats() {
+    for stats in stats() {
+        log!(LogLevel::Debug, "{}\n", stats);
+    }
+}
+
 #[alloc_error_handler]
 fn alloc_error_handler(layout: core::alloc::Layout) -> ! {
     panic!("allocation error {:?}", layout)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn caches_are_named_after_their_size() {
+        for stats in stats() {
+            assert_eq!(stats.name, format!("block-{}", stats.object_size));
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use crate::ioapic::Polarity;
+use crate::logger::Level as LogLevel;
+use crate::sync::once_cell::OnceCell;
+use crate::{acpi, log};
+use bit_field::BitField;
+use modular_bitfield::prelude::*;
+use volatile::Volatile;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const IA32_APIC_BASE_GLOBAL_ENABLE: usize = 11;
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// LVT delivery modes
+const DELIVERY_MODE_NMI: u8 = 0b100;
+// Processor ID in the MADT meaning all processors
+const ALL_PROCESSORS: u8 = 0xff;
+
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+
+pub fn local_apic() -> &'static LocalApic {
+    LOCAL_APIC.get()
+}
+
+// Offsets from the Local APIC base address
+#[derive(Debug, Clone, Copy)]
+#[repr(u64)]
+enum Register {
+    Id = 0x20,
+    Version = 0x30,
+    EndOfInterrupt = 0xb0,
+    SpuriousInterruptVector = 0xf0,
+    InterruptCommandLow = 0x300,
+    InterruptCommandHigh = 0x310,
+    LvtTimer = 0x320,
+    LvtLint0 = 0x350,
+    LvtLint1 = 0x360,
+    LvtError = 0x370,
+    TimerInitialCount = 0x380,
+    TimerCurrentCount = 0x390,
+    TimerDivideConfiguration = 0x3e0,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Lvt {
+    Timer,
+    Lint0,
+    Lint1,
+    Error,
+}
+
+impl Lvt {
+    fn register(self) -> Register {
+        match self {
+            Lvt::Timer => Register::LvtTimer,
+            Lvt::Lint0 => Register::LvtLint0,
+            Lvt::Lint1 => Register::LvtLint1,
+            Lvt::Error => Register::LvtError,
+        }
+    }
+}
+
+#[derive(BitfieldSpecifier, Debug, Clone, Copy, PartialEq, Eq)]
+#[bits = 2]
+pub enum TimerMode {
+    OneShot = 0b00,
+    Periodic = 0b01,
+    TscDeadline = 0b10,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+pub enum TimerDivide {
+    By1 = 0b1011,
+    By2 = 0b0000,
+    By4 = 0b0001,
+    By8 = 0b0010,
+    By16 = 0b0011,
+    By32 = 0b1000,
+    By64 = 0b1001,
+    By128 = 0b1010,
+}
+
+#[bitfield(bits = 32)]
+#[derive(Debug, Clone, Copy)]
+pub struct LvtEntry {
+    pub vector: B8,
+    pub delivery_mode: B3,
+    #[skip]
+    __: B1,
+    #[skip(setters)]
+    pub delivery_status: bool,
+    pub active_low: bool,
+    #[skip(setters)]
+    pub remote_irr: bool,
+    pub level_triggered: bool,
+    pub masked: bool,
+    #[bits = 2]
+    pub timer_mode: TimerMode,
+    #[skip]
+    __: B13,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ApicVersion {
+    pub version: u8,
+    pub max_lvt_entry: u8,
+}
+
+pub struct LocalApic {
+    base: u64,
+}
+
+impl LocalApic {
+    fn register(&self, register: Register) -> Volatile<&'static mut u32> {
+        Volatile::new(unsafe {
+            ((self.base + register as u64) as *mut u32)
+                .as_mut()
+                .unwrap()
+        })
+    }
+
+    fn read(&self, register: Register) -> u32 {
+        self.register(register).read()
+    }
+
+    fn write(&self, register: Register, value: u32) {
+        self.register(register).write(value)
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn id(&self) -> u32 {
+        self.read(Register::Id) >> 24
+    }
+
+    pub fn version(&self) -> ApicVersion {
+        let value = self.read(Register::Version);
+        ApicVersion {
+            version: value.get_bits(0..8) as u8,
+            max_lvt_entry: value.get_bits(16..24) as u8,
+        }
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(Register::EndOfInterrupt, 0);
+    }
+
+    pub fn enable(&self, spurious_vector: u8) {
+        let mut value = self.read(Register::SpuriousInterruptVector);
+        value.set_bits(0..8, u32::from(spurious_vector));
+        // APIC Software Enable
+        value.set_bit(8, true);
+        self.write(Register::SpuriousInterruptVector, value);
+    }
+
+    pub fn read_lvt(&self, lvt: Lvt) -> LvtEntry {
+        LvtEntry::from_bytes(self.read(lvt.register()).to_le_bytes())
+    }
+
+    pub fn write_lvt(&self, lvt: Lvt, entry: LvtEntry) {
+        self.write(lvt.register(), u32::from_le_bytes(entry.into_bytes()));
+    }
+
+    pub fn set_timer_divide(&self, divide: TimerDivide) {
+        self.write(Register::TimerDivideConfiguration, divide as u32);
+    }
+
+    pub fn set_timer_initial_count(&self, count: u32) {
+        self.write(Register::TimerInitialCount, count);
+    }
+
+    pub fn timer_current_count(&self) -> u32 {
+        self.read(Register::TimerCurrentCount)
+    }
+
+    pub fn send_ipi(&self, destination: u32, command: u32) {
+        self.write(Register::InterruptCommandHigh, destination << 24);
+        self.write(Register::InterruptCommandLow, command);
+        // Wait until the IPI has been delivered
+        while self.read(Register::InterruptCommandLow).get_bit(12) {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+pub extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // Spurious interrupts must not be acknowledged with EOI
+}
+
+// Programs the LINT pins which the firmware wired to NMI
+fn configure_lint_nmis(lapic: &LocalApic) {
+    let madt = match acpi::tables().and_then(|tables| tables.madt.as_ref()) {
+        Some(madt) => madt,
+        None => return,
+    };
+    let processor_id = madt
+        .local_apics
+        .iter()
+        .find(|entry| u32::from(entry.apic_id) == lapic.id())
+        .map(|entry| entry.processor_id);
+
+    for nmi in madt
+        .local_apic_nmis
+        .iter()
+        .filter(|nmi| nmi.processor_id == ALL_PROCESSORS || Some(nmi.processor_id) == processor_id)
+    {
+        let lvt = match nmi.lint {
+            0 => Lvt::Lint0,
+            1 => Lvt::Lint1,
+            _ => continue,
+        };
+        // NMIs are always edge triggered
+        lapic.write_lvt(
+            lvt,
+            LvtEntry::new()
+                .with_delivery_mode(DELIVERY_MODE_NMI)
+                .with_active_low(nmi.polarity == Some(Polarity::ActiveLow))
+                .with_masked(false),
+        );
+    }
+}
+
+pub fn init() {
+    let mut msr = Msr::new(IA32_APIC_BASE);
+    let mut apic_base = unsafe { msr.read() };
+    if !apic_base.get_bit(IA32_APIC_BASE_GLOBAL_ENABLE) {
+        apic_base.set_bit(IA32_APIC_BASE_GLOBAL_ENABLE, true);
+        unsafe { msr.write(apic_base) };
+    }
+
+    LOCAL_APIC.init_once(|| LocalApic {
+        base: apic_base.get_bits(12..52) << 12,
+    });
+
+    let lapic = local_apic();
+    lapic.enable(SPURIOUS_VECTOR);
+    configure_lint_nmis(lapic);
+    let version = lapic.version();
+    log!(
+        LogLevel::Info,
+        "Local APIC: id = {}, base = {:08x}, version = {:02x}, max LVT = {}\n",
+        lapic.id(),
+        lapic.base(),
+        version.version,
+        version.max_lvt_entry
+    );
+}
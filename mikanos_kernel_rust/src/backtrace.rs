@@ -0,0 +1,110 @@
+use crate::paging::as_phys_addr;
+use crate::printk;
+use core::arch::asm;
+use core::{ptr, str};
+
+// Must match KSYMS_SIZE in Makefile
+const SYMBOL_TABLE_SIZE: usize = 1024 * 1024;
+const MAX_FRAMES: usize = 32;
+
+// Filled in after linking by the Makefile with the output of `nm --numeric-sort`.
+// Each line is "<hex address> <symbol>\n" and the rest of the section is zero.
+#[no_mangle]
+#[used]
+#[link_section = ".ksyms"]
+static mut KERNEL_SYMBOLS: [u8; SYMBOL_TABLE_SIZE] = [0; SYMBOL_TABLE_SIZE];
+
+fn symbol_table() -> &'static [u8] {
+    let table = unsafe { &*ptr::addr_of!(KERNEL_SYMBOLS) };
+    let len = table.iter().position(|b| *b == 0).unwrap_or(table.len());
+    &table[..len]
+}
+
+/// Returns the symbol containing `addr` and the offset from its start.
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    let mut found = None;
+    for line in symbol_table().split(|b| *b == b'\n') {
+        let line = match str::from_utf8(line) {
+            Ok(line) => line,
+            Err(_) => continue,
+        };
+        let (sym_addr, name) = match line.split_once(' ') {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let sym_addr = match u64::from_str_radix(sym_addr, 16) {
+            Ok(sym_addr) => sym_addr,
+            Err(_) => continue,
+        };
+        // Symbols are sorted by address
+        if sym_addr > addr {
+            break;
+        }
+        found = Some((name, addr - sym_addr));
+    }
+    found
+}
+
+fn is_valid_frame_pointer(rbp: u64) -> bool {
+    rbp != 0 && rbp % 8 == 0 && as_phys_addr(x86_64::VirtAddr::new_truncate(rbp + 8)).is_some()
+}
+
+/// Calls `f` with the return address of each frame reachable from `rbp`.
+pub fn walk_stack(mut rbp: u64, mut f: impl FnMut(u64)) {
+    for _ in 0..MAX_FRAMES {
+        if !is_valid_frame_pointer(rbp) {
+            return;
+        }
+        let (next_rbp, return_address) = unsafe {
+            let frame = rbp as *const u64;
+            (frame.read(), frame.add(1).read())
+        };
+        if return_address == 0 {
+            return;
+        }
+        f(return_address);
+        // Stacks grow downwards, so callers always have a higher frame pointer
+        if next_rbp <= rbp {
+            return;
+        }
+        rbp = next_rbp;
+    }
+}
+
+fn print_frame(index: usize, addr: u64, lookup_addr: u64) {
+    match resolve(lookup_addr) {
+        Some((name, offset)) => printk!(
+            "  #{:<2} {:016x} {}+{:#x}\n",
+            index,
+            addr,
+            name,
+            offset + addr - lookup_addr
+        ),
+        None => printk!("  #{:<2} {:016x} ???\n", index, addr),
+    }
+}
+
+/// Prints the stack starting from an interrupted context.
+pub fn print_backtrace_from(rip: u64, rbp: u64) {
+    printk!("Backtrace:\n");
+    print_frame(0, rip, rip);
+    let mut index = 1;
+    walk_stack(rbp, |return_address| {
+        // The call instruction precedes the return address
+        print_frame(index, return_address, return_address - 1);
+        index += 1;
+    });
+}
+
+/// Prints the stack of the caller.
+#[inline(never)]
+pub fn print_backtrace() {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    printk!("Backtrace:\n");
+    let mut index = 0;
+    walk_stack(rbp, |return_address| {
+        print_frame(index, return_address, return_address - 1);
+        index += 1;
+    });
+}
@@ -39,6 +39,13 @@ pub fn _printk(args: fmt::Arguments) {
     }
 }
 
+/// Releases the console lock held by code that will never resume, e.g. on a fatal exception.
+pub unsafe fn force_unlock_console() {
+    if let Some(console) = CONSOLE.as_ref() {
+        console.force_unlock();
+    }
+}
+
 impl Console {
     pub fn new(graphics: &Graphics, fg_color: &PixelColor, bg_color: &PixelColor) -> Self {
         Console {
@@ -1,5 +1,5 @@
-use crate::logger;
 use crate::logger::Level;
+use crate::{logger, tsc};
 use core::{ptr, slice, str};
 
 #[no_mangle]
@@ -21,6 +21,11 @@ extern "C" fn mikanos_log(level: i32, msg: *const u8, msg_len: usize) -> i32 {
     msg_len as i32
 }
 
+#[no_mangle]
+extern "C" fn mikanos_now_ns() -> u64 {
+    tsc::now_nanos()
+}
+
 extern "C" {
     fn __errno() -> *mut i32;
 }
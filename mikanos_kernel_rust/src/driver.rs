@@ -0,0 +1,275 @@
+use crate::logger::Level as LogLevel;
+use crate::pci::{Device, Devices};
+use crate::sync::once_cell::OnceCell;
+use crate::{log, xhc};
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use spin::mutex::SpinMutex;
+
+// Drivers built into the kernel. New drivers only need to be listed here.
+static BUILTIN_DRIVERS: &[&PciDriver] = &[&xhc::DRIVER];
+
+/// Matches PCI devices. Fields left as None match any value.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PciDeviceId {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub base_class: Option<u8>,
+    pub sub_class: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl PciDeviceId {
+    pub const fn device(vendor_id: u16, device_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            base_class: None,
+            sub_class: None,
+            prog_if: None,
+        }
+    }
+
+    pub const fn class(base_class: u8, sub_class: u8, prog_if: u8) -> Self {
+        Self {
+            vendor_id: None,
+            device_id: None,
+            base_class: Some(base_class),
+            sub_class: Some(sub_class),
+            prog_if: Some(prog_if),
+        }
+    }
+
+    pub const fn with_vendor(self, vendor_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            ..self
+        }
+    }
+
+    pub fn matches(&self, device: &Device) -> bool {
+        fn field_matches<T: PartialEq>(expected: Option<T>, actual: T) -> bool {
+            expected.map_or(true, |expected| expected == actual)
+        }
+
+        field_matches(self.vendor_id, device.vendor_id)
+            && field_matches(self.device_id, device.device_id)
+            && field_matches(self.base_class, device.base_class())
+            && field_matches(self.sub_class, device.sub_class())
+            && field_matches(self.prog_if, device.prog_if())
+    }
+
+    // An exact device ID beats a vendor, which beats a class match
+    fn specificity(&self) -> u32 {
+        [
+            (self.device_id.is_some(), 32),
+            (self.vendor_id.is_some(), 16),
+            (self.prog_if.is_some(), 4),
+            (self.sub_class.is_some(), 2),
+            (self.base_class.is_some(), 1),
+        ]
+        .iter()
+        .filter(|(specified, _)| *specified)
+        .map(|(_, score)| score)
+        .sum()
+    }
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub id_table: &'static [PciDeviceId],
+    /// Takes the device. Returning Err lets less specific drivers try it.
+    pub probe: fn(&Device) -> Result<(), ()>,
+    pub remove: Option<fn(&Device)>,
+}
+
+impl PciDriver {
+    /// Score of the most specific entry matching the device.
+    fn match_score(&self, device: &Device) -> Option<u32> {
+        self.id_table
+            .iter()
+            .filter(|id| id.matches(device))
+            .map(|id| id.specificity())
+            .max()
+    }
+}
+
+struct Registry {
+    drivers: Vec<&'static PciDriver>,
+    // Driver bound to each device, indexed like DEVICES
+    bindings: Vec<Option<&'static PciDriver>>,
+}
+
+static DEVICES: OnceCell<Devices> = OnceCell::uninit();
+static REGISTRY: SpinMutex<Registry> = SpinMutex::new(Registry {
+    drivers: Vec::new(),
+    bindings: Vec::new(),
+});
+
+/// All enumerated PCI devices.
+pub fn devices() -> &'static [Device] {
+    DEVICES.try_get().map_or(&[], |devices| devices.as_slice())
+}
+
+pub fn bound_driver(device: &Device) -> Option<&'static str> {
+    let index = device_index(device)?;
+    REGISTRY
+        .lock()
+        .bindings
+        .get(index)
+        .copied()
+        .flatten()
+        .map(|d| d.name)
+}
+
+fn device_index(device: &Device) -> Option<usize> {
+    devices()
+        .iter()
+        .position(|d| (d.bus, d.device, d.function) == (device.bus, device.device, device.function))
+}
+
+// Offers unbound devices to drivers, the most specific matches first, so that a
+// driver which accepts only one device gets the preferred one.
+fn probe_unbound(drivers: &[&'static PciDriver]) {
+    let mut candidates = Vec::new();
+    {
+        let registry = REGISTRY.lock();
+        for (index, device) in devices().iter().enumerate() {
+            if registry.bindings[index].is_some() {
+                continue;
+            }
+            for driver in drivers {
+                if let Some(score) = driver.match_score(device) {
+                    candidates.push((score, index, *driver));
+                }
+            }
+        }
+    }
+    candidates.sort_by_key(|(score, _, _)| Reverse(*score));
+
+    for (_, index, driver) in candidates {
+        if REGISTRY.lock().bindings[index].is_some() {
+            continue;
+        }
+        let device = &devices()[index];
+        // The lock is not held so that probe can query the registry
+        match (driver.probe)(device) {
+            Ok(()) => {
+                REGISTRY.lock().bindings[index] = Some(driver);
+                log!(LogLevel::Info, "{}: bound to {}\n", driver.name, device);
+            }
+            Err(()) => {
+                log!(
+                    LogLevel::Debug,
+                    "{}: probe failed for {}\n",
+                    driver.name,
+                    device
+                );
+            }
+        }
+    }
+}
+
+/// Registers a driver and probes the devices nobody has taken yet.
+pub fn register_driver(driver: &'static PciDriver) {
+    REGISTRY.lock().drivers.push(driver);
+    if DEVICES.try_get().is_ok() {
+        probe_unbound(&[driver]);
+    }
+}
+
+/// Calls `remove` of the driver bound to the device and unbinds it.
+pub fn unbind(device: &Device) -> Result<(), ()> {
+    let index = device_index(device).ok_or(())?;
+    let driver = REGISTRY.lock().bindings[index].take().ok_or(())?;
+    if let Some(remove) = driver.remove {
+        remove(&devices()[index]);
+    }
+    Ok(())
+}
+
+/// Takes the enumerated devices and binds the built-in drivers to them.
+pub fn init(devices: Devices) {
+    REGISTRY.lock().bindings = devices.iter().map(|_| None).collect();
+    DEVICES.init_once(|| devices);
+
+    REGISTRY.lock().drivers.extend_from_slice(BUILTIN_DRIVERS);
+    let drivers = REGISTRY.lock().drivers.clone();
+    probe_unbound(&drivers);
+
+    print_devices();
+}
+
+/// Logs the devices like `lspci -v`. The details only appear at the debug level.
+pub fn print_devices() {
+    for device in devices() {
+        log!(LogLevel::Info, "{}\n", device);
+        log!(LogLevel::Debug, "{}", device.details());
+        if let Some(name) = bound_driver(device) {
+            log!(LogLevel::Debug, "\tKernel driver in use: {}\n", name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VENDOR: u16 = 0x8086;
+    const OTHER_VENDOR: u16 = 0x1b36;
+    // xHCI, revision 1
+    const XHCI_CLASS: u32 = 0x0c03_3001;
+
+    fn xhci(vendor_id: u16, device_id: u16) -> Device {
+        Device::fake(vendor_id, device_id, XHCI_CLASS)
+    }
+
+    #[test_case]
+    fn matches_only_specified_fields() {
+        let id = PciDeviceId::class(0x0c, 0x03, 0x30);
+        assert!(id.matches(&xhci(VENDOR, 0x1234)));
+        assert!(id.matches(&xhci(OTHER_VENDOR, 0x000d)));
+        assert!(!id.matches(&Device::fake(VENDOR, 0x1234, 0x0c03_2000)));
+        assert!(!id.with_vendor(VENDOR).matches(&xhci(OTHER_VENDOR, 0x000d)));
+        assert!(PciDeviceId::default().matches(&xhci(OTHER_VENDOR, 0x000d)));
+    }
+
+    #[test_case]
+    fn device_beats_vendor_beats_class() {
+        let class = PciDeviceId::class(0x0c, 0x03, 0x30);
+        let vendor = class.with_vendor(VENDOR);
+        let device = PciDeviceId::device(VENDOR, 0x1234);
+        assert!(device.specificity() > vendor.specificity());
+        assert!(vendor.specificity() > class.specificity());
+        // A vendor alone is more specific than a full class code
+        assert!(PciDeviceId::default().with_vendor(VENDOR).specificity() > class.specificity());
+        assert_eq!(PciDeviceId::default().specificity(), 0);
+    }
+
+    #[test_case]
+    fn match_score_takes_most_specific_matching_entry() {
+        static ID_TABLE: [PciDeviceId; 3] = [
+            PciDeviceId::class(0x0c, 0x03, 0x30),
+            PciDeviceId::class(0x0c, 0x03, 0x30).with_vendor(VENDOR),
+            PciDeviceId::device(OTHER_VENDOR, 0x1234),
+        ];
+        let driver = PciDriver {
+            name: "test",
+            id_table: &ID_TABLE,
+            probe: |_| Err(()),
+            remove: None,
+        };
+        assert_eq!(
+            driver.match_score(&xhci(VENDOR, 0x1234)),
+            Some(ID_TABLE[1].specificity())
+        );
+        assert_eq!(
+            driver.match_score(&xhci(OTHER_VENDOR, 0x000d)),
+            Some(ID_TABLE[0].specificity())
+        );
+        assert_eq!(
+            driver.match_score(&Device::fake(VENDOR, 0x5678, 0x0200_0000)),
+            None
+        );
+    }
+}
@@ -0,0 +1,233 @@
+// Checks done by KernelAllocator when built with the heap-debug feature.
+//
+// Every allocation is laid out as
+//   [padding][Header][front red zone][object][back red zone]
+// with the header right before the front red zone, so that it is found from the
+// object pointer alone, even when dealloc is given a wrong layout.
+
+use core::alloc::Layout;
+use core::mem;
+use core::ptr;
+
+const RED_ZONE_SIZE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xfd;
+// Catches reads of memory that was never written
+const ALLOCATED_POISON: u8 = 0xcd;
+// Catches use after free
+const FREED_POISON: u8 = 0xdd;
+
+const ALLOCATED_MAGIC: u64 = 0xa110_ca7e_d0b1_ec75;
+const FREED_MAGIC: u64 = 0xf4ee_d0b1_ec75_dead;
+
+#[repr(C)]
+struct Header {
+    // The slab and frame free lists write their links at the start of a freed
+    // block, which may be here
+    _free_list_link: [u64; 2],
+    magic: u64,
+    size: usize,
+    align: usize,
+}
+
+const fn round_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
+
+fn front_size(layout: &Layout) -> usize {
+    round_up(mem::size_of::<Header>() + RED_ZONE_SIZE, layout.align())
+}
+
+/// The layout actually requested from the allocator for `layout`.
+pub fn padded_layout(layout: Layout) -> Layout {
+    Layout::from_size_align(
+        front_size(&layout) + layout.size() + RED_ZONE_SIZE,
+        layout.align(),
+    )
+    .unwrap()
+}
+
+unsafe fn header(object: *mut u8) -> *mut Header {
+    object.sub(RED_ZONE_SIZE + mem::size_of::<Header>()) as *mut Header
+}
+
+fn red_zone_intact(start: *const u8) -> bool {
+    (0..RED_ZONE_SIZE).all(|i| unsafe { start.add(i).read() } == RED_ZONE_BYTE)
+}
+
+/// Sets up a block allocated with `padded_layout(layout)` and returns the object in it.
+///
+/// # Safety
+/// `block` must be a live allocation of `padded_layout(layout)`.
+pub unsafe fn on_alloc(block: *mut u8, layout: Layout) -> *mut u8 {
+    if block.is_null() {
+        return block;
+    }
+    let object = block.add(front_size(&layout));
+    header(object).write(Header {
+        _free_list_link: [0; 2],
+        magic: ALLOCATED_MAGIC,
+        size: layout.size(),
+        align: layout.align(),
+    });
+    ptr::write_bytes(object.sub(RED_ZONE_SIZE), RED_ZONE_BYTE, RED_ZONE_SIZE);
+    ptr::write_bytes(object, ALLOCATED_POISON, layout.size());
+    ptr::write_bytes(object.add(layout.size()), RED_ZONE_BYTE, RED_ZONE_SIZE);
+    object
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Corruption {
+    DoubleFree,
+    InvalidFree,
+    LayoutMismatch,
+    Underflow,
+    Overflow,
+}
+
+unsafe fn check(object: *mut u8, layout: Layout) -> Result<(), Corruption> {
+    let header = header(object);
+    match (*header).magic {
+        ALLOCATED_MAGIC => {}
+        FREED_MAGIC => return Err(Corruption::DoubleFree),
+        _ => return Err(Corruption::InvalidFree),
+    }
+    let size = (*header).size;
+    if size != layout.size() || (*header).align != layout.align() {
+        return Err(Corruption::LayoutMismatch);
+    }
+    if !red_zone_intact(object.sub(RED_ZONE_SIZE)) {
+        return Err(Corruption::Underflow);
+    }
+    if !red_zone_intact(object.add(size)) {
+        return Err(Corruption::Overflow);
+    }
+    Ok(())
+}
+
+/// Checks an object being freed and returns the block to give back to the allocator.
+/// Panics on a double free, a layout mismatch or an overwritten red zone.
+///
+/// # Safety
+/// `object` must have been returned by `on_alloc`.
+pub unsafe fn on_dealloc(object: *mut u8, layout: Layout) -> *mut u8 {
+    let header = header(object);
+    let size = (*header).size;
+    match check(object, layout) {
+        Ok(()) => {}
+        Err(Corruption::DoubleFree) => {
+            panic!("heap: double free of {:p} (size {})", object, size)
+        }
+        Err(Corruption::InvalidFree) => panic!(
+            "heap: corrupted header or invalid free of {:p} (size {})",
+            object,
+            layout.size()
+        ),
+        Err(Corruption::LayoutMismatch) => panic!(
+            "heap: {:p} allocated with size {} align {}, but freed with size {} align {}",
+            object,
+            size,
+            (*header).align,
+            layout.size(),
+            layout.align()
+        ),
+        Err(Corruption::Underflow) => panic!(
+            "heap: underflow before {:p} (size {}), front red zone overwritten",
+            object, size
+        ),
+        Err(Corruption::Overflow) => panic!(
+            "heap: overflow past {:p} (size {}), back red zone overwritten",
+            object, size
+        ),
+    }
+
+    (*header).magic = FREED_MAGIC;
+    ptr::write_bytes(object, FREED_POISON, size);
+    object.sub(front_size(&layout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stands in for a block from the allocator
+    #[repr(align(64))]
+    struct Block([u8; 256]);
+
+    fn allocate(block: &mut Block, layout: Layout) -> *mut u8 {
+        assert!(padded_layout(layout).size() <= block.0.len());
+        unsafe { on_alloc(block.0.as_mut_ptr(), layout) }
+    }
+
+    #[test_case]
+    fn rounds_the_front_up_to_the_alignment() {
+        for align in [1, 8, 16, 64] {
+            let layout = Layout::from_size_align(24, align).unwrap();
+            let front = front_size(&layout);
+            assert_eq!(front % align, 0);
+            assert!(front >= mem::size_of::<Header>() + RED_ZONE_SIZE);
+            assert!(front < mem::size_of::<Header>() + RED_ZONE_SIZE + align);
+            let padded = padded_layout(layout);
+            assert_eq!(padded.size(), front + 24 + RED_ZONE_SIZE);
+            assert_eq!(padded.align(), align);
+        }
+    }
+
+    #[test_case]
+    fn frees_an_intact_object() {
+        let mut block = Block([0; 256]);
+        let layout = Layout::from_size_align(24, 16).unwrap();
+        let object = allocate(&mut block, layout);
+        assert_eq!(object as usize % 16, 0);
+        assert_eq!(unsafe { object.read() }, ALLOCATED_POISON);
+        assert_eq!(unsafe { check(object, layout) }, Ok(()));
+        assert_eq!(unsafe { on_dealloc(object, layout) }, block.0.as_mut_ptr());
+        assert_eq!(unsafe { object.add(23).read() }, FREED_POISON);
+    }
+
+    #[test_case]
+    fn detects_a_double_free() {
+        let mut block = Block([0; 256]);
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let object = allocate(&mut block, layout);
+        unsafe { on_dealloc(object, layout) };
+        assert_eq!(
+            unsafe { check(object, layout) },
+            Err(Corruption::DoubleFree)
+        );
+    }
+
+    #[test_case]
+    fn detects_a_wrong_layout() {
+        let mut block = Block([0; 256]);
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let object = allocate(&mut block, layout);
+        assert_eq!(
+            unsafe { check(object, Layout::from_size_align(32, 8).unwrap()) },
+            Err(Corruption::LayoutMismatch)
+        );
+    }
+
+    #[test_case]
+    fn detects_overwritten_red_zones() {
+        let mut block = Block([0; 256]);
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let object = allocate(&mut block, layout);
+        unsafe { object.add(24).write(0) };
+        assert_eq!(unsafe { check(object, layout) }, Err(Corruption::Overflow));
+
+        let object = allocate(&mut block, layout);
+        unsafe { object.sub(1).write(0) };
+        assert_eq!(unsafe { check(object, layout) }, Err(Corruption::Underflow));
+    }
+
+    #[test_case]
+    fn detects_a_free_of_an_unknown_object() {
+        let mut block = Block([0; 256]);
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let object = unsafe { block.0.as_mut_ptr().add(front_size(&layout)) };
+        assert_eq!(
+            unsafe { check(object, layout) },
+            Err(Corruption::InvalidFree)
+        );
+    }
+}
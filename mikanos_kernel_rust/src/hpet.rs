@@ -0,0 +1,262 @@
+use crate::ioapic::{Polarity, TriggerMode};
+use crate::logger::Level as LogLevel;
+use crate::paging::as_virt_addr;
+use crate::sync::once_cell::OnceCell;
+use crate::{acpi, apic, ioapic, log};
+use bit_field::BitField;
+use volatile::Volatile;
+use x86_64::{PhysAddr, VirtAddr};
+
+// Used when the ACPI HPET table is not available
+const DEFAULT_HPET_BASE: u64 = 0xfed0_0000;
+
+// General registers
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIGURATION: u64 = 0x010;
+const REG_MAIN_COUNTER: u64 = 0x0f0;
+
+// Per timer registers
+const fn reg_timer_configuration(index: u8) -> u64 {
+    0x100 + 0x20 * index as u64
+}
+const fn reg_timer_comparator(index: u8) -> u64 {
+    0x108 + 0x20 * index as u64
+}
+const fn reg_timer_fsb_route(index: u8) -> u64 {
+    0x110 + 0x20 * index as u64
+}
+
+// General capabilities
+const CAP_COUNT_SIZE_64: usize = 13;
+
+// General configuration
+const CONF_ENABLE: usize = 0;
+
+// Timer configuration and capabilities
+const TIMER_LEVEL_TRIGGERED: usize = 1;
+const TIMER_INTERRUPT_ENABLE: usize = 2;
+const TIMER_PERIODIC: usize = 3;
+const TIMER_PERIODIC_CAPABLE: usize = 4;
+const TIMER_VALUE_SET: usize = 6;
+const TIMER_ROUTE: core::ops::Range<usize> = 9..14;
+const TIMER_FSB_ENABLE: usize = 14;
+const TIMER_FSB_CAPABLE: usize = 15;
+const TIMER_ROUTE_CAPABILITIES: core::ops::Range<usize> = 32..64;
+
+// The specification limits the counter period to 100ns
+const MAX_PERIOD_FEMTOSECONDS: u64 = 100_000_000;
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.try_get().ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    OneShot,
+    Periodic,
+}
+
+pub struct Hpet {
+    base: u64,
+    registers: VirtAddr,
+    period_femtoseconds: u64,
+    timer_count: u8,
+    // u32::MAX when the main counter is only 32 bits wide
+    counter_mask: u64,
+}
+
+impl Hpet {
+    fn register(&self, offset: u64) -> Volatile<&'static mut u64> {
+        Volatile::new(unsafe {
+            (self.registers + offset)
+                .as_mut_ptr::<u64>()
+                .as_mut()
+                .unwrap()
+        })
+    }
+
+    fn read(&self, offset: u64) -> u64 {
+        self.register(offset).read()
+    }
+
+    fn write(&self, offset: u64, value: u64) {
+        self.register(offset).write(value)
+    }
+
+    fn set_enabled(&self, enabled: bool) {
+        let mut conf = self.read(REG_CONFIGURATION);
+        conf.set_bit(CONF_ENABLE, enabled);
+        self.write(REG_CONFIGURATION, conf);
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn timer_count(&self) -> u8 {
+        self.timer_count
+    }
+
+    /// Main counter ticks per second.
+    pub fn frequency(&self) -> u64 {
+        FEMTOSECONDS_PER_SECOND / self.period_femtoseconds
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.read(REG_MAIN_COUNTER)
+    }
+
+    pub fn ticks_from_milliseconds(&self, milliseconds: u64) -> u64 {
+        milliseconds * self.frequency() / 1000
+    }
+
+    /// Ticks from `start` to `end`, across one wrap of the main counter.
+    fn ticks_between(&self, start: u64, end: u64) -> u64 {
+        end.wrapping_sub(start) & self.counter_mask
+    }
+
+    pub fn wait_milliseconds(&self, milliseconds: u64) {
+        let ticks = self.ticks_from_milliseconds(milliseconds);
+        let start = self.counter();
+        while self.ticks_between(start, self.counter()) < ticks {
+            core::hint::spin_loop();
+        }
+    }
+
+    // Prefers FSB (MSI) delivery and falls back to an IOAPIC input.
+    fn route_interrupt(&self, index: u8, config: &mut u64, vector: u8) -> Result<(), ()> {
+        if config.get_bit(TIMER_FSB_CAPABLE) {
+            let address = 0xfee0_0000 | u64::from(apic::local_apic().id()) << 12;
+            self.write(
+                reg_timer_fsb_route(index),
+                address << 32 | u64::from(vector),
+            );
+            config.set_bit(TIMER_FSB_ENABLE, true);
+            config.set_bit(TIMER_LEVEL_TRIGGERED, false);
+            return Ok(());
+        }
+
+        let route_capabilities = config.get_bits(TIMER_ROUTE_CAPABILITIES);
+        // Avoid the ISA IRQ lines when another input is allowed
+        let gsi = (0..32u32)
+            .rev()
+            .filter(|gsi| route_capabilities.get_bit(*gsi as usize))
+            .find(|gsi| ioapic::ioapic_for_gsi(*gsi).is_some())
+            .ok_or(())?;
+        ioapic::route_gsi(gsi, vector, TriggerMode::Edge, Polarity::ActiveHigh)?;
+        config.set_bits(TIMER_ROUTE, u64::from(gsi));
+        config.set_bit(TIMER_FSB_ENABLE, false);
+        config.set_bit(TIMER_LEVEL_TRIGGERED, false);
+        Ok(())
+    }
+
+    /// Starts comparator `index` to raise `vector` after `ticks` main counter ticks,
+    /// once or every `ticks` ticks.
+    pub fn start_timer(
+        &self,
+        index: u8,
+        mode: TimerMode,
+        ticks: u64,
+        vector: u8,
+    ) -> Result<(), ()> {
+        if index >= self.timer_count || ticks == 0 {
+            return Err(());
+        }
+        let mut config = self.read(reg_timer_configuration(index));
+        if mode == TimerMode::Periodic && !config.get_bit(TIMER_PERIODIC_CAPABLE) {
+            return Err(());
+        }
+
+        config.set_bit(TIMER_INTERRUPT_ENABLE, false);
+        self.write(reg_timer_configuration(index), config);
+        self.route_interrupt(index, &mut config, vector)?;
+
+        match mode {
+            TimerMode::OneShot => {
+                config.set_bit(TIMER_PERIODIC, false);
+                config.set_bit(TIMER_INTERRUPT_ENABLE, true);
+                self.write(reg_timer_configuration(index), config);
+                self.write(
+                    reg_timer_comparator(index),
+                    self.counter().wrapping_add(ticks) & self.counter_mask,
+                );
+            }
+            TimerMode::Periodic => {
+                // The counter is stopped so that the first deadline is not already past
+                self.set_enabled(false);
+                config.set_bit(TIMER_PERIODIC, true);
+                config.set_bit(TIMER_VALUE_SET, true);
+                config.set_bit(TIMER_INTERRUPT_ENABLE, true);
+                self.write(reg_timer_configuration(index), config);
+                // With VALUE_SET, the first write sets the deadline and the second the period
+                self.write(
+                    reg_timer_comparator(index),
+                    self.counter().wrapping_add(ticks) & self.counter_mask,
+                );
+                self.write(reg_timer_comparator(index), ticks);
+                self.set_enabled(true);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stop_timer(&self, index: u8) {
+        if index >= self.timer_count {
+            return;
+        }
+        let mut config = self.read(reg_timer_configuration(index));
+        config.set_bit(TIMER_INTERRUPT_ENABLE, false);
+        self.write(reg_timer_configuration(index), config);
+    }
+}
+
+pub fn init() -> Result<(), ()> {
+    let base = acpi::tables()
+        .and_then(|tables| tables.hpet)
+        .map_or(DEFAULT_HPET_BASE, |hpet| hpet.base_address);
+    let registers = match as_virt_addr(PhysAddr::new(base)) {
+        Some(registers) => registers,
+        None => {
+            log!(LogLevel::Warn, "HPET: {:08x} is not mapped\n", base);
+            return Err(());
+        }
+    };
+    let hpet = Hpet {
+        base,
+        registers,
+        period_femtoseconds: 0,
+        timer_count: 0,
+        counter_mask: u64::MAX,
+    };
+    let capabilities = hpet.read(REG_CAPABILITIES);
+    let period = capabilities.get_bits(32..64);
+    // Unbacked MMIO reads as all ones
+    if period == 0 || period > MAX_PERIOD_FEMTOSECONDS {
+        log!(LogLevel::Info, "HPET is not found\n");
+        return Err(());
+    }
+    let hpet = Hpet {
+        period_femtoseconds: period,
+        timer_count: capabilities.get_bits(8..13) as u8 + 1,
+        counter_mask: if capabilities.get_bit(CAP_COUNT_SIZE_64) {
+            u64::MAX
+        } else {
+            u64::from(u32::MAX)
+        },
+        ..hpet
+    };
+    hpet.set_enabled(true);
+    log!(
+        LogLevel::Info,
+        "HPET: base = {:08x}, {} Hz, {} timers\n",
+        hpet.base,
+        hpet.frequency(),
+        hpet.timer_count
+    );
+
+    HPET.init_once(|| hpet);
+    Ok(())
+}
@@ -1,48 +1,380 @@
+use crate::logger::Level as LogLevel;
 use crate::sync::once_cell::OnceCell;
-use crate::xhc;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use crate::{apic, backtrace, console, log, printk, segments};
+use alloc::boxed::Box;
+use core::fmt;
+use core::fmt::Formatter;
+use core::ops::Range;
+use spin::RwLock;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{
+    InterruptDescriptorTable, InterruptStackFrameValue, PageFaultErrorCode,
+};
+use x86_64::VirtAddr;
 
 static IDT: OnceCell<InterruptDescriptorTable> = OnceCell::uninit();
 
-extern "x86-interrupt" fn breakpoint_handler(_stack_name: InterruptStackFrame) {}
+// Vectors below this are reserved for CPU exceptions
+pub const FIRST_DEVICE_VECTOR: u8 = 0x20;
+const VECTOR_COUNT: usize = 256;
 
-extern "x86-interrupt" fn page_fault_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: PageFaultErrorCode,
-) {
+pub type InterruptHandler = Box<dyn Fn() + Send + Sync>;
+
+struct VectorTable {
+    allocated: [bool; VECTOR_COUNT],
+    handlers: [Option<InterruptHandler>; VECTOR_COUNT],
+}
+
+static VECTORS: RwLock<VectorTable> = RwLock::new(VectorTable::new());
+
+impl VectorTable {
+    const fn new() -> Self {
+        const NO_HANDLER: Option<InterruptHandler> = None;
+        let mut allocated = [false; VECTOR_COUNT];
+        let mut vector = 0;
+        while vector < FIRST_DEVICE_VECTOR as usize {
+            allocated[vector] = true;
+            vector += 1;
+        }
+        allocated[apic::SPURIOUS_VECTOR as usize] = true;
+        Self {
+            allocated,
+            handlers: [NO_HANDLER; VECTOR_COUNT],
+        }
+    }
+}
+
+// The table is only written with interrupts disabled so that a handler never
+// spins on a lock held by the code it interrupted.
+fn with_vectors_mut<R>(f: impl FnOnce(&mut VectorTable) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut VECTORS.write()))
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
+// CPU exceptions and the spurious interrupt never get a device handler
+fn is_reserved(vector: u8) -> bool {
+    vector < FIRST_DEVICE_VECTOR || vector == apic::SPURIOUS_VECTOR
 }
 
-extern "x86-interrupt" fn segment_not_present_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
+/// Reserves an unused vector for a device interrupt.
+pub fn allocate_vector() -> Result<u8, ()> {
+    with_vectors_mut(|vectors| {
+        let vector = vectors
+            .allocated
+            .iter()
+            .position(|allocated| !allocated)
+            .ok_or(())?;
+        vectors.allocated[vector] = true;
+        Ok(vector as u8)
+    })
+}
+
+/// Marks vectors that are owned by something other than a registered handler, e.g. the legacy PIC.
+pub fn reserve_vectors(range: Range<u8>) -> Result<(), ()> {
+    with_vectors_mut(|vectors| {
+        let range = range.start as usize..range.end as usize;
+        if vectors.allocated[range.clone()]
+            .iter()
+            .any(|allocated| *allocated)
+        {
+            return Err(());
+        }
+        vectors.allocated[range].fill(true);
+        Ok(())
+    })
+}
+
+/// Releases a vector and its handler.
+pub fn free_vector(vector: u8) {
+    with_vectors_mut(|vectors| {
+        if is_reserved(vector) {
+            return;
+        }
+        vectors.handlers[vector as usize] = None;
+        vectors.allocated[vector as usize] = false;
+    });
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+/// Sets the handler for an allocated vector. The handler runs with interrupts disabled,
+/// must not register handlers itself and does not need to signal EOI.
+pub fn register_handler<F>(vector: u8, handler: F) -> Result<(), ()>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let handler: InterruptHandler = Box::new(handler);
+    with_vectors_mut(|vectors| {
+        if is_reserved(vector) || !vectors.allocated[vector as usize] {
+            return Err(());
+        }
+        vectors.handlers[vector as usize] = Some(handler);
+        Ok(())
+    })
+}
+
+/// Removes the handler of an allocated vector. The vector stays allocated.
+pub fn unregister_handler(vector: u8) -> Result<(), ()> {
+    with_vectors_mut(|vectors| {
+        if is_reserved(vector) || !vectors.allocated[vector as usize] {
+            return Err(());
+        }
+        vectors.handlers[vector as usize] = None;
+        Ok(())
+    })
+}
+
+/// Allocates a vector and registers `handler` for it.
+pub fn request_interrupt<F>(handler: F) -> Result<u8, ()>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let vector = allocate_vector()?;
+    if let Err(e) = register_handler(vector, handler) {
+        free_vector(vector);
+        return Err(e);
+    }
+    Ok(vector)
+}
+
+#[no_mangle]
+extern "C" fn interrupt_dispatch(vector: u64) {
+    {
+        let vectors = VECTORS.read();
+        match &vectors.handlers[vector as usize] {
+            Some(handler) => handler(),
+            None => {
+                log!(
+                    LogLevel::Warn,
+                    "Unhandled interrupt: vector = {:#04x}\n",
+                    vector
+                );
+            }
+        }
+    }
+    apic::local_apic().end_of_interrupt();
+}
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "Divide Error",
+    "Debug",
+    "Non-maskable Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "Bound Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection Fault",
+    "Page Fault",
+    "Reserved",
+    "x87 Floating-Point Exception",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point Exception",
+    "Virtualization Exception",
+    "Control Protection Exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception",
+    "VMM Communication Exception",
+    "Security Exception",
+    "Reserved",
+];
+
+const VECTOR_DEBUG: u64 = 1;
+const VECTOR_BREAKPOINT: u64 = 3;
+const VECTOR_PAGE_FAULT: u64 = 14;
+
+// Saved by exception_common in asm.s. The field order must match the push order there.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+impl ExceptionContext {
+    pub fn name(&self) -> &'static str {
+        EXCEPTION_NAMES
+            .get(self.vector as usize)
+            .copied()
+            .unwrap_or("Unknown")
+    }
+}
+
+impl fmt::Display for ExceptionContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "RAX={:016x} RBX={:016x} RCX={:016x}\n\
+             RDX={:016x} RSI={:016x} RDI={:016x}\n\
+             RBP={:016x} R8 ={:016x} R9 ={:016x}\n\
+             R10={:016x} R11={:016x} R12={:016x}\n\
+             R13={:016x} R14={:016x} R15={:016x}\n",
+            self.rax,
+            self.rbx,
+            self.rcx,
+            self.rdx,
+            self.rsi,
+            self.rdi,
+            self.rbp,
+            self.r8,
+            self.r9,
+            self.r10,
+            self.r11,
+            self.r12,
+            self.r13,
+            self.r14,
+            self.r15
+        )
+    }
+}
+
+fn print_exception(context: &ExceptionContext) {
+    printk!(
+        "EXCEPTION: {} (vector={}, error_code={:#x})\n",
+        context.name(),
+        context.vector,
+        context.error_code
+    );
+    let frame = &context.stack_frame;
+    printk!(
+        "RIP={:016x} CS={:04x} RFLAGS={:016x}\n",
+        frame.instruction_pointer.as_u64(),
+        frame.code_segment,
+        frame.cpu_flags
+    );
+    printk!(
+        "RSP={:016x} SS={:04x}\n",
+        frame.stack_pointer.as_u64(),
+        frame.stack_segment
+    );
+    if context.vector == VECTOR_PAGE_FAULT {
+        printk!(
+            "CR2={:016x} {:?}\n",
+            Cr2::read().as_u64(),
+            PageFaultErrorCode::from_bits_truncate(context.error_code)
+        );
+    }
+    printk!("{}", context);
+}
+
+#[no_mangle]
+extern "C" fn exception_handler(context: &mut ExceptionContext) {
+    match context.vector {
+        // Traps: report and continue with the next instruction.
+        VECTOR_DEBUG | VECTOR_BREAKPOINT => {
+            print_exception(context);
+        }
+        _ => {
+            // The faulting code may have held the console lock. We never return to it.
+            unsafe { console::force_unlock_console() };
+            print_exception(context);
+            backtrace::print_backtrace_from(
+                context.stack_frame.instruction_pointer.as_u64(),
+                context.rbp,
+            );
+            crate::hlt_loop();
+        }
+    }
+}
+
+extern "C" {
+    static exception_stub_table: [u64; 32];
+}
+
+fn exception_stub(vector: usize) -> VirtAddr {
+    VirtAddr::new(unsafe { exception_stub_table[vector] })
+}
+
+extern "C" {
+    static interrupt_stub_table: [u64; VECTOR_COUNT - FIRST_DEVICE_VECTOR as usize];
+}
+
+fn interrupt_stub(vector: usize) -> VirtAddr {
+    VirtAddr::new(unsafe { interrupt_stub_table[vector - FIRST_DEVICE_VECTOR as usize] })
 }
 
 pub fn init() {
     IDT.init_once(|| {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.general_protection_fault
-            .set_handler_fn(general_protection_fault_handler);
-        idt.segment_not_present
-            .set_handler_fn(segment_not_present_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
-        idt[0x40 as usize].set_handler_fn(xhc::xhc_interrupt_handler);
+        unsafe {
+            idt.divide_error.set_handler_addr(exception_stub(0));
+            idt.debug.set_handler_addr(exception_stub(1));
+            idt.non_maskable_interrupt
+                .set_handler_addr(exception_stub(2))
+                .set_stack_index(segments::NMI_IST_INDEX);
+            idt.breakpoint.set_handler_addr(exception_stub(3));
+            idt.overflow.set_handler_addr(exception_stub(4));
+            idt.bound_range_exceeded.set_handler_addr(exception_stub(5));
+            idt.invalid_opcode.set_handler_addr(exception_stub(6));
+            idt.device_not_available.set_handler_addr(exception_stub(7));
+            idt.double_fault
+                .set_handler_addr(exception_stub(8))
+                .set_stack_index(segments::DOUBLE_FAULT_IST_INDEX);
+            idt.invalid_tss.set_handler_addr(exception_stub(10));
+            idt.segment_not_present.set_handler_addr(exception_stub(11));
+            idt.stack_segment_fault.set_handler_addr(exception_stub(12));
+            idt.general_protection_fault
+                .set_handler_addr(exception_stub(13));
+            idt.page_fault.set_handler_addr(exception_stub(14));
+            idt.x87_floating_point.set_handler_addr(exception_stub(16));
+            idt.alignment_check.set_handler_addr(exception_stub(17));
+            idt.machine_check
+                .set_handler_addr(exception_stub(18))
+                .set_stack_index(segments::MACHINE_CHECK_IST_INDEX);
+            idt.simd_floating_point.set_handler_addr(exception_stub(19));
+            idt.virtualization.set_handler_addr(exception_stub(20));
+            idt.vmm_communication_exception
+                .set_handler_addr(exception_stub(29));
+            idt.security_exception.set_handler_addr(exception_stub(30));
+            for vector in FIRST_DEVICE_VECTOR as usize..VECTOR_COUNT {
+                idt[vector].set_handler_addr(interrupt_stub(vector));
+            }
+        }
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(apic::spurious_interrupt_handler);
         idt
     });
     IDT.get().load();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn handlers_only_change_on_allocated_device_vectors() {
+        // Page fault
+        assert!(unregister_handler(0x0e).is_err());
+        assert!(unregister_handler(apic::SPURIOUS_VECTOR).is_err());
+
+        let vector = request_interrupt(|| {}).unwrap();
+        assert!(unregister_handler(vector).is_ok());
+        assert!(register_handler(vector, || {}).is_ok());
+        free_vector(vector);
+        assert!(unregister_handler(vector).is_err());
+        assert!(register_handler(vector, || {}).is_err());
+    }
+}
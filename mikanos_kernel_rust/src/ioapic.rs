@@ -0,0 +1,308 @@
+use crate::logger::Level as LogLevel;
+use crate::paging::as_virt_addr;
+use crate::sync::once_cell::OnceCell;
+use crate::{acpi, apic, interrupt, log};
+use alloc::vec;
+use alloc::vec::Vec;
+use bit_field::BitField;
+use modular_bitfield::prelude::*;
+use spin::mutex::SpinMutex;
+use volatile::Volatile;
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+// Used when the MADT is not available
+const DEFAULT_IOAPIC_BASE: u64 = 0xfec0_0000;
+
+// Memory mapped registers
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+// Indirect registers selected by IOREGSEL
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+// The legacy PICs are remapped to these vectors so that spurious interrupts
+// from them never look like CPU exceptions.
+const PIC_MASTER_VECTOR: u8 = 0x20;
+const PIC_SLAVE_VECTOR: u8 = 0x28;
+
+const ISA_IRQ_COUNT: u8 = 16;
+
+static IOAPICS: OnceCell<Vec<SpinMutex<IoApic>>> = OnceCell::uninit();
+
+pub fn ioapics() -> &'static [SpinMutex<IoApic>] {
+    IOAPICS.get()
+}
+
+/// Returns the IOAPIC which has the input for `gsi`.
+pub fn ioapic_for_gsi(gsi: u32) -> Option<&'static SpinMutex<IoApic>> {
+    ioapics()
+        .iter()
+        .find(|ioapic| ioapic.lock().gsi_range().contains(&gsi))
+}
+
+#[derive(BitfieldSpecifier, Debug, Clone, Copy, PartialEq, Eq)]
+#[bits = 3]
+pub enum DeliveryMode {
+    Fixed = 0b000,
+    LowestPriority = 0b001,
+    Smi = 0b010,
+    Nmi = 0b100,
+    Init = 0b101,
+    ExtInt = 0b111,
+}
+
+#[derive(BitfieldSpecifier, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh = 0,
+    ActiveLow = 1,
+}
+
+#[derive(BitfieldSpecifier, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge = 0,
+    Level = 1,
+}
+
+#[bitfield(bits = 64)]
+#[derive(Debug, Clone, Copy)]
+pub struct RedirectionEntry {
+    pub vector: B8,
+    #[bits = 3]
+    pub delivery_mode: DeliveryMode,
+    pub logical_destination: bool,
+    #[skip(setters)]
+    pub delivery_pending: bool,
+    #[bits = 1]
+    pub polarity: Polarity,
+    #[skip(setters)]
+    pub remote_irr: bool,
+    #[bits = 1]
+    pub trigger_mode: TriggerMode,
+    pub masked: bool,
+    #[skip]
+    __: B39,
+    pub destination: B8,
+}
+
+pub struct IoApic {
+    base: VirtAddr,
+    id: u8,
+    gsi_base: u32,
+    redirection_entries: u32,
+}
+
+impl IoApic {
+    fn register(&self, offset: u64) -> Volatile<&'static mut u32> {
+        Volatile::new(unsafe { (self.base + offset).as_mut_ptr::<u32>().as_mut().unwrap() })
+    }
+
+    fn read(&mut self, reg: u32) -> u32 {
+        self.register(IOREGSEL).write(reg);
+        self.register(IOWIN).read()
+    }
+
+    fn write(&mut self, reg: u32, value: u32) {
+        self.register(IOREGSEL).write(reg);
+        self.register(IOWIN).write(value);
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn gsi_range(&self) -> core::ops::Range<u32> {
+        self.gsi_base..self.gsi_base + self.redirection_entries
+    }
+
+    fn entry_index(&self, gsi: u32) -> Result<u32, ()> {
+        if self.gsi_range().contains(&gsi) {
+            Ok(gsi - self.gsi_base)
+        } else {
+            Err(())
+        }
+    }
+
+    pub fn read_entry(&mut self, gsi: u32) -> Result<RedirectionEntry, ()> {
+        let reg = REG_REDIRECTION_TABLE + 2 * self.entry_index(gsi)?;
+        let low = self.read(reg);
+        let high = self.read(reg + 1);
+        Ok(RedirectionEntry::from_bytes(
+            (u64::from(high) << 32 | u64::from(low)).to_le_bytes(),
+        ))
+    }
+
+    pub fn write_entry(&mut self, gsi: u32, entry: RedirectionEntry) -> Result<(), ()> {
+        let reg = REG_REDIRECTION_TABLE + 2 * self.entry_index(gsi)?;
+        let value = u64::from_le_bytes(entry.into_bytes());
+        // Keep the entry masked while the two halves disagree
+        let low = self.read(reg);
+        self.write(reg, low | 1 << 16);
+        self.write(reg + 1, (value >> 32) as u32);
+        self.write(reg, value as u32);
+        Ok(())
+    }
+
+    pub fn set_masked(&mut self, gsi: u32, masked: bool) -> Result<(), ()> {
+        let mut entry = self.read_entry(gsi)?;
+        entry.set_masked(masked);
+        self.write_entry(gsi, entry)
+    }
+}
+
+/// Routes a global system interrupt to `vector` on the bootstrap processor.
+pub fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    trigger_mode: TriggerMode,
+    polarity: Polarity,
+) -> Result<(), ()> {
+    let entry = RedirectionEntry::new()
+        .with_vector(vector)
+        .with_delivery_mode(DeliveryMode::Fixed)
+        .with_logical_destination(false)
+        .with_polarity(polarity)
+        .with_trigger_mode(trigger_mode)
+        .with_masked(false)
+        .with_destination(apic::local_apic().id() as u8);
+    ioapic_for_gsi(gsi)
+        .ok_or(())?
+        .lock()
+        .write_entry(gsi, entry)
+}
+
+pub fn set_gsi_masked(gsi: u32, masked: bool) -> Result<(), ()> {
+    ioapic_for_gsi(gsi)
+        .ok_or(())?
+        .lock()
+        .set_masked(gsi, masked)
+}
+
+/// Resolves the GSI, trigger mode and polarity of a legacy ISA IRQ.
+pub fn isa_irq_to_gsi(irq: u8) -> Result<(u32, TriggerMode, Polarity), ()> {
+    if irq >= ISA_IRQ_COUNT {
+        return Err(());
+    }
+    // ISA interrupts are identity mapped, edge triggered and active high unless overridden
+    let iso = acpi::tables()
+        .and_then(|tables| tables.madt.as_ref())
+        .and_then(|madt| madt.interrupt_source_override(irq));
+    Ok(match iso {
+        Some(iso) => (
+            iso.gsi,
+            iso.trigger_mode.unwrap_or(TriggerMode::Edge),
+            iso.polarity.unwrap_or(Polarity::ActiveHigh),
+        ),
+        None => (u32::from(irq), TriggerMode::Edge, Polarity::ActiveHigh),
+    })
+}
+
+/// Routes a legacy ISA IRQ to `vector` on the bootstrap processor.
+pub fn route_irq(irq: u8, vector: u8) -> Result<(), ()> {
+    let (gsi, trigger_mode, polarity) = isa_irq_to_gsi(irq)?;
+    route_gsi(gsi, vector, trigger_mode, polarity)
+}
+
+pub fn mask_irq(irq: u8) -> Result<(), ()> {
+    set_gsi_masked(isa_irq_to_gsi(irq)?.0, true)
+}
+
+pub fn unmask_irq(irq: u8) -> Result<(), ()> {
+    set_gsi_masked(isa_irq_to_gsi(irq)?.0, false)
+}
+
+fn disable_legacy_pic() {
+    let mut master_command = Port::<u8>::new(0x20);
+    let mut master_data = Port::<u8>::new(0x21);
+    let mut slave_command = Port::<u8>::new(0xa0);
+    let mut slave_data = Port::<u8>::new(0xa1);
+    // Writing to an unused port gives the PICs time to react
+    let mut wait = Port::<u8>::new(0x80);
+
+    unsafe {
+        // ICW1: start initialization, ICW4 needed
+        master_command.write(0x11);
+        wait.write(0);
+        slave_command.write(0x11);
+        wait.write(0);
+        // ICW2: vector offsets
+        master_data.write(PIC_MASTER_VECTOR);
+        wait.write(0);
+        slave_data.write(PIC_SLAVE_VECTOR);
+        wait.write(0);
+        // ICW3: the slave is connected to IRQ2 of the master
+        master_data.write(1 << 2);
+        wait.write(0);
+        slave_data.write(2);
+        wait.write(0);
+        // ICW4: 8086 mode
+        master_data.write(0x01);
+        wait.write(0);
+        slave_data.write(0x01);
+        wait.write(0);
+        // Mask all interrupts
+        master_data.write(0xff);
+        slave_data.write(0xff);
+    }
+}
+
+fn probe(base: u64, gsi_base: u32) -> Result<IoApic, ()> {
+    let mut ioapic = IoApic {
+        base: as_virt_addr(PhysAddr::new(base)).ok_or(())?,
+        id: 0,
+        gsi_base,
+        redirection_entries: 0,
+    };
+    ioapic.id = ioapic.read(REG_ID).get_bits(24..28) as u8;
+    ioapic.redirection_entries = ioapic.read(REG_VERSION).get_bits(16..24) + 1;
+    for gsi in ioapic.gsi_range() {
+        ioapic.set_masked(gsi, true).expect("GSI is out of range");
+    }
+    log!(
+        LogLevel::Info,
+        "IOAPIC: id = {}, base = {:08x}, GSI = {:?}\n",
+        ioapic.id,
+        base,
+        ioapic.gsi_range()
+    );
+    Ok(ioapic)
+}
+
+pub fn init() {
+    interrupt::reserve_vectors(PIC_MASTER_VECTOR..PIC_SLAVE_VECTOR + 8)
+        .expect("Vectors for the legacy PIC are already in use");
+    disable_legacy_pic();
+
+    let ioapics = match acpi::tables().and_then(|tables| tables.madt.as_ref()) {
+        Some(madt) if !madt.io_apics.is_empty() => madt
+            .io_apics
+            .iter()
+            .filter_map(
+                |entry| match probe(u64::from(entry.address), entry.gsi_base) {
+                    Ok(ioapic) => Some(SpinMutex::new(ioapic)),
+                    Err(()) => {
+                        log!(
+                            LogLevel::Warn,
+                            "IOAPIC: {:08x} is not mapped\n",
+                            entry.address
+                        );
+                        None
+                    }
+                },
+            )
+            .collect(),
+        _ => {
+            log!(
+                LogLevel::Warn,
+                "IOAPIC: MADT is not available, using the default base\n"
+            );
+            vec![SpinMutex::new(
+                probe(DEFAULT_IOAPIC_BASE, 0).expect("Default IOAPIC base is not mapped"),
+            )]
+        }
+    };
+
+    IOAPICS.init_once(|| ioapics);
+}
@@ -2,24 +2,44 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::run_tests)]
+#![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod backtrace;
 pub mod console;
 pub mod cxx_support;
+pub mod driver;
 pub mod fonts;
 pub mod graphics;
+#[cfg(any(test, feature = "heap-debug"))]
+pub mod heap_debug;
+pub mod hpet;
 pub mod interrupt;
+pub mod ioapic;
 pub mod logger;
 pub mod memory;
 pub mod memory_manager;
 pub mod mouse;
 pub mod paging;
 pub mod pci;
+pub mod pci_ids;
+pub mod pit;
+pub mod power;
 pub mod queue;
+pub mod rtc;
 pub mod segments;
+pub mod slab;
 pub mod sync;
+#[cfg(test)]
+pub mod testing;
+pub mod timer;
+pub mod tsc;
 pub mod xhc;
 
 use crate::console::initialize_console;
@@ -27,16 +47,22 @@ use crate::graphics::{FrameBuffer, Graphics, PixelColor, Vector2D};
 use crate::logger::Level as LogLevel;
 use crate::memory::{MemoryDescriptor, MemoryMap, MemoryType};
 use crate::queue::{event_queue, QueueEventType};
+use crate::timer::TickSource;
+use core::ffi::c_void;
 use core::panic::PanicInfo;
 
-fn hlt_loop() {
+// Switch to TickSource::Hpet on machines where the Local APIC timer is unreliable
+const TICK_SOURCE: TickSource = TickSource::LocalApic;
+
+pub fn hlt_loop() -> ! {
+    x86_64::instructions::interrupts::disable();
     loop {
         x86_64::instructions::hlt();
     }
 }
 
 #[no_mangle]
-extern "C" fn kernel_main2(fb: *mut FrameBuffer, mc: *const MemoryMap) {
+extern "C" fn kernel_main2(fb: *mut FrameBuffer, mc: *const MemoryMap, acpi_table: *const c_void) {
     let fb_a = unsafe { *fb };
     let bg_color = PixelColor(45, 118, 237);
     let fg_color = PixelColor(255, 255, 255);
@@ -90,12 +116,54 @@ extern "C" fn kernel_main2(fb: *mut FrameBuffer, mc: *const MemoryMap) {
     paging::init();
     let mc = unsafe { *mc };
     memory_manager::init(&mc);
+    memory_manager::print_memory_map();
     queue::init();
+    // Interrupt routing and timer calibration fall back to legacy defaults without ACPI
+    let acpi_available = acpi::init(acpi_table).is_ok();
 
     interrupt::init();
+    apic::init();
+    // Nothing the loader left behind is used from here on. The frame buffer config and
+    // the memory map have been copied, the kernel runs on its own stack, GDT and IDT
+    // instead of the firmware's ones in boot services memory, and the Local APIC is
+    // set up by the kernel. acpi::init keeps its own copy of what it read from the tables.
+    if acpi_available {
+        memory_manager::reclaim_acpi_memory();
+    }
+    memory_manager::reclaim_boot_services();
+    log!(
+        LogLevel::Info,
+        "Physical memory:\n{}",
+        memory_manager::stats()
+    );
+    ioapic::init();
+    // The HPET is optional and only used when selected as the tick source or as a fallback
+    let _ = hpet::init();
+    timer::init(TICK_SOURCE).expect("Failed to start the timer");
+    tsc::init();
+    log!(LogLevel::Info, "Boot time: {}\n", rtc::now());
+    // Tests run with the memory manager, the heap and the timers up, before any device
+    #[cfg(test)]
+    test_main();
+
     log!(LogLevel::Info, "Load PCI devices\n");
+    let scan_start = tsc::Instant::now();
     let devices = pci::scan_all_bus().expect("Failed to scan PCI devices");
-    xhc::init(&devices).expect("Failed to init xHC device");
+    let bind_start = tsc::Instant::now();
+    log!(
+        LogLevel::Info,
+        "PCI: {} functions scanned in {} us\n",
+        devices.len(),
+        (bind_start - scan_start).as_micros()
+    );
+    driver::init(devices);
+    log!(
+        LogLevel::Info,
+        "PCI: drivers bound in {} us\n",
+        bind_start.elapsed().as_micros()
+    );
+    allocator::print_stats();
+    log!(LogLevel::Debug, "{}\n", timer::cache_stats());
 
     loop {
         // cli
@@ -122,6 +190,8 @@ extern "C" fn kernel_main2(fb: *mut FrameBuffer, mc: *const MemoryMap) {
                     }
                 }
             }
+            QueueEventType::TimerTick => timer::report_lost_timeouts(),
+            QueueEventType::TimerTimeout(timeout) => timeout.dispatch(),
         }
     }
 
@@ -133,7 +203,13 @@ extern "C" fn kernel_main2(fb: *mut FrameBuffer, mc: *const MemoryMap) {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    printk!("Panic!! {}", info);
-
-    loop {}
+    // The panicking code may have held the console lock. We never return to it.
+    unsafe { console::force_unlock_console() };
+    printk!("Panic!! {}\n", info);
+    backtrace::print_backtrace();
+
+    #[cfg(test)]
+    testing::fail();
+    #[cfg(not(test))]
+    hlt_loop();
 }
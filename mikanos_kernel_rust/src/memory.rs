@@ -24,10 +24,8 @@ pub struct MemoryDescriptor {
 
 impl MemoryDescriptor {
     pub fn physical_end(&self) -> *const usize {
-        unsafe {
-            self.physical_start
-                .add(self.number_of_pages as usize * 4096)
-        }
+        // physical_start points to usize, so the offset must be counted in bytes
+        (self.physical_start as usize + self.number_of_pages as usize * 4096) as *const usize
     }
 }
 
@@ -38,7 +36,7 @@ impl core::fmt::Display for MemoryDescriptor {
             "type={:?}, phys = {:?} - {:?}, pages = {}, attr = {:08x}",
             self.memory_type,
             self.physical_start,
-            unsafe { self.physical_end().sub(1) },
+            (self.physical_end() as usize - 1) as *const usize,
             self.number_of_pages,
             self.attribute
         )
@@ -1,4 +1,9 @@
-use crate::{MemoryDescriptor, MemoryMap, MemoryType};
+use crate::logger::Level as LogLevel;
+use crate::paging::{self, as_virt_addr};
+use crate::sync::once_cell::OnceCell;
+use crate::{log, MemoryDescriptor, MemoryMap, MemoryType};
+use arrayvec::ArrayVec;
+use core::fmt;
 use core::mem;
 use spin::mutex::{SpinMutex, SpinMutexGuard};
 
@@ -6,10 +11,21 @@ use spin::mutex::{SpinMutex, SpinMutexGuard};
 const MAX_PHYSICAL_MEMORY_BYTES: usize = 128 * 1024 * 1024 * 1024;
 const FRAME_COUNT: usize = MAX_PHYSICAL_MEMORY_BYTES / FrameId::SIZE;
 
+// Blocks of order n are 2^n frames. Order 10 is 4MiB.
+pub const MAX_ORDER: usize = 10;
+const ORDER_COUNT: usize = MAX_ORDER + 1;
+
 type MapLine = usize;
 
 const BITS_PER_MAP_LINE: usize = 8 * mem::size_of::<MapLine>();
-const MAP_LINE_COUNT: usize = FRAME_COUNT / BITS_PER_MAP_LINE;
+// Order n needs FRAME_COUNT >> n bits, so all orders together need less than twice FRAME_COUNT
+const MAP_LINE_COUNT: usize = 2 * FRAME_COUNT / BITS_PER_MAP_LINE;
+
+// End of a free list
+const NIL: usize = usize::MAX;
+
+const MAX_MEMORY_DESCRIPTORS: usize = 256;
+const MEMORY_TYPE_COUNT: usize = MemoryType::EfiMaxMemoryType as usize;
 
 #[derive(Debug)]
 pub struct FrameId(usize);
@@ -30,122 +46,703 @@ impl FrameId {
     pub const SIZE: usize = 4096;
 }
 
-pub struct BitmapMemoryManager {
-    alloc_map: [MapLine; MAP_LINE_COUNT],
+// Stored in the first frame of every free block
+struct FreeBlock {
+    prev: usize,
+    next: usize,
+}
+
+const fn order_bit_offset(order: usize) -> usize {
+    let mut offset = 0;
+    let mut i = 0;
+    while i < order {
+        offset += FRAME_COUNT >> i;
+        i += 1;
+    }
+    offset
+}
+
+const fn frames_of_order(order: usize) -> usize {
+    1 << order
+}
+
+// The largest order whose block fits in `num_frames`
+fn floor_order(num_frames: usize) -> usize {
+    (mem::size_of::<usize>() * 8 - 1 - num_frames.leading_zeros() as usize).min(MAX_ORDER)
+}
+
+// The smallest order whose block holds `num_frames`
+fn ceil_order(num_frames: usize) -> usize {
+    num_frames.next_power_of_two().trailing_zeros() as usize
+}
+
+pub struct BuddyMemoryManager {
+    // One bit per block of each order, set while the block is on a free list
+    free_map: [MapLine; MAP_LINE_COUNT],
+    free_lists: [usize; ORDER_COUNT],
+    free_frames: usize,
+    // Bit n is set once the regions of MemoryType n have been handed to the allocator
+    reclaimed_types: u32,
     pub begin: FrameId,
     pub end: FrameId,
 }
 
-impl BitmapMemoryManager {
+impl BuddyMemoryManager {
     pub const fn new() -> Self {
         Self {
-            alloc_map: [0; MAP_LINE_COUNT],
+            free_map: [0; MAP_LINE_COUNT],
+            free_lists: [NIL; ORDER_COUNT],
+            free_frames: 0,
+            reclaimed_types: 0,
             begin: FrameId::MIN,
             end: FrameId::MAX,
         }
     }
 
-    pub fn allocate(&mut self, num_frames: usize) -> Result<FrameId, ()> {
-        let mut start_frame_id = self.begin.0;
-        'search: loop {
-            for i in 0..num_frames {
-                if start_frame_id + 1 >= self.end.0 {
-                    // フレームがもうない
-                    return Err(())?;
+    fn is_free(&self, frame: usize, order: usize) -> bool {
+        let bit = order_bit_offset(order) + (frame >> order);
+        (self.free_map[bit / BITS_PER_MAP_LINE] & (1 << (bit % BITS_PER_MAP_LINE))) != 0
+    }
+
+    fn set_free(&mut self, frame: usize, order: usize, free: bool) {
+        let bit = order_bit_offset(order) + (frame >> order);
+        if free {
+            self.free_map[bit / BITS_PER_MAP_LINE] |= 1 << (bit % BITS_PER_MAP_LINE);
+        } else {
+            self.free_map[bit / BITS_PER_MAP_LINE] &= !(1 << (bit % BITS_PER_MAP_LINE));
+        }
+    }
+
+    fn node(frame: usize) -> *mut FreeBlock {
+        as_virt_addr(FrameId(frame).to_physical_address())
+            .unwrap()
+            .as_mut_ptr()
+    }
+
+    fn push_block(&mut self, frame: usize, order: usize) {
+        let head = self.free_lists[order];
+        unsafe {
+            Self::node(frame).write(FreeBlock {
+                prev: NIL,
+                next: head,
+            });
+            if head != NIL {
+                (*Self::node(head)).prev = frame;
+            }
+        }
+        self.free_lists[order] = frame;
+        self.set_free(frame, order, true);
+        self.free_frames += frames_of_order(order);
+    }
+
+    fn remove_block(&mut self, frame: usize, order: usize) {
+        let FreeBlock { prev, next } = unsafe { Self::node(frame).read() };
+        if prev == NIL {
+            self.free_lists[order] = next;
+        } else {
+            unsafe { (*Self::node(prev)).next = next };
+        }
+        if next != NIL {
+            unsafe { (*Self::node(next)).prev = prev };
+        }
+        self.set_free(frame, order, false);
+        self.free_frames -= frames_of_order(order);
+    }
+
+    // Frees a naturally aligned block, merging it with its buddies
+    fn free_block(&mut self, mut frame: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = frame ^ frames_of_order(order);
+            if buddy < self.begin.0 || buddy >= self.end.0 || !self.is_free(buddy, order) {
+                break;
+            }
+            self.remove_block(buddy, order);
+            frame = frame.min(buddy);
+            order += 1;
+        }
+        self.push_block(frame, order);
+    }
+
+    // Splits a free block of `from_order` down to `to_order`, keeping the first half
+    fn split_block(&mut self, frame: usize, from_order: usize, to_order: usize) {
+        for order in (to_order..from_order).rev() {
+            self.push_block(frame + frames_of_order(order), order);
+        }
+    }
+
+    /// Finds the free block containing `frame`.
+    fn find_free_block(&self, frame: usize) -> Option<(usize, usize)> {
+        (0..ORDER_COUNT)
+            .map(|order| (frame & !(frames_of_order(order) - 1), order))
+            .find(|(block, order)| self.is_free(*block, *order))
+    }
+
+    // The first set bit of free_map in [begin, end), a map line at a time
+    fn first_free_bit(&self, begin: usize, end: usize) -> Option<usize> {
+        let mut bit = begin;
+        while bit < end {
+            let line = self.free_map[bit / BITS_PER_MAP_LINE] >> (bit % BITS_PER_MAP_LINE);
+            if line != 0 {
+                let found = bit + line.trailing_zeros() as usize;
+                return (found < end).then_some(found);
+            }
+            bit = (bit / BITS_PER_MAP_LINE + 1) * BITS_PER_MAP_LINE;
+        }
+        None
+    }
+
+    /// Finds the first free block starting in `(frame, end)`, when `frame` itself is
+    /// not free. Allocated frames are skipped without looking at them one by one.
+    fn next_free_block(&self, frame: usize, end: usize) -> Option<usize> {
+        (0..ORDER_COUNT)
+            .filter_map(|order| {
+                let first = (frame >> order) + 1;
+                let last = (end - 1) >> order;
+                if first > last {
+                    return None;
                 }
+                let offset = order_bit_offset(order);
+                self.first_free_bit(offset + first, offset + last + 1)
+                    .map(|bit| (bit - offset) << order)
+            })
+            .min()
+    }
+
+    // Requests larger than the largest order take consecutive free blocks of MAX_ORDER
+    fn allocate_large(&mut self, num_frames: usize) -> Result<FrameId, ()> {
+        let block_frames = frames_of_order(MAX_ORDER);
+        let num_blocks = (num_frames + block_frames - 1) / block_frames;
+        let first = (self.begin.0 + block_frames - 1) / block_frames;
+        let last = self.end.0 / block_frames;
 
-                if self.get_bit(FrameId(start_frame_id + i)) {
-                    // 使用中なので次を検索
-                    start_frame_id = start_frame_id + i + 1;
-                    continue 'search;
+        let mut run_start = first;
+        for block in first..last {
+            if !self.is_free(block * block_frames, MAX_ORDER) {
+                run_start = block + 1;
+                continue;
+            }
+            if block + 1 - run_start == num_blocks {
+                for b in run_start..=block {
+                    self.remove_block(b * block_frames, MAX_ORDER);
                 }
+                let start = run_start * block_frames;
+                self.free(
+                    FrameId(start + num_frames),
+                    num_blocks * block_frames - num_frames,
+                );
+                return Ok(FrameId(start));
             }
-            let new_start_frame_id = FrameId(start_frame_id);
-            self.mark_allocated(&new_start_frame_id, num_frames);
-            return Ok(new_start_frame_id);
         }
+        Err(())
     }
 
+    pub fn allocate(&mut self, num_frames: usize) -> Result<FrameId, ()> {
+        if num_frames == 0 {
+            return Err(());
+        }
+        let order = ceil_order(num_frames);
+        if order > MAX_ORDER {
+            return self.allocate_large(num_frames);
+        }
+
+        let found_order = (order..ORDER_COUNT)
+            .find(|o| self.free_lists[*o] != NIL)
+            .ok_or(())?;
+        let frame = self.free_lists[found_order];
+        self.remove_block(frame, found_order);
+        self.split_block(frame, found_order, order);
+        // Return the unused tail of a request which is not a power of two
+        self.free(
+            FrameId(frame + num_frames),
+            frames_of_order(order) - num_frames,
+        );
+        Ok(FrameId(frame))
+    }
+
+    /// Frees any range of frames. It does not need to match an allocation.
+    /// Frames which are free already are skipped, since freeing them again would
+    /// corrupt the free lists.
     pub fn free(&mut self, start_frame: FrameId, num_frames: usize) {
-        for i in 0..num_frames {
-            self.set_bit(FrameId(start_frame.0 + i), false);
+        let mut frame = start_frame.0.max(self.begin.0);
+        let end = (start_frame.0 + num_frames).min(self.end.0);
+        while frame < end {
+            if let Some((block, order)) = self.find_free_block(frame) {
+                log!(
+                    LogLevel::Error,
+                    "memory_manager: frame {:#x} is already free\n",
+                    frame
+                );
+                frame = block + frames_of_order(order);
+                continue;
+            }
+            // The largest aligned block that starts at `frame` and fits in the range
+            let mut order = floor_order(end - frame).min(frame.trailing_zeros() as usize);
+            // and ends before any free frame
+            if let Some(next) = self.next_free_block(frame, frame + frames_of_order(order)) {
+                order = order.min(floor_order(next - frame));
+            }
+            self.free_block(frame, order);
+            frame += frames_of_order(order);
         }
     }
 
     pub fn mark_allocated(&mut self, start_frame: &FrameId, num_frames: usize) {
-        for i in 0..num_frames {
-            self.set_bit(FrameId(start_frame.0 + i), true);
+        let end = start_frame.0 + num_frames;
+        let mut frame = start_frame.0;
+        while frame < end {
+            match self.find_free_block(frame) {
+                Some((block, order)) => {
+                    // Only the part of the block in the range is taken
+                    let block_end = block + frames_of_order(order);
+                    self.remove_block(block, order);
+                    self.free(FrameId(block), frame - block);
+                    if block_end > end {
+                        self.free(FrameId(end), block_end - end);
+                    }
+                    frame = block_end.min(end);
+                }
+                None => match self.next_free_block(frame, end) {
+                    Some(next) => frame = next,
+                    None => break,
+                },
+            }
         }
     }
 
+    /// Rounds up, so that a partial frame at the end is also reserved.
     pub fn mark_allocated_in_bytes(&mut self, start_frame: &FrameId, bytes: usize) {
-        self.mark_allocated(start_frame, bytes / FrameId::SIZE);
+        self.mark_allocated(start_frame, (bytes + FrameId::SIZE - 1) / FrameId::SIZE);
     }
 
     pub fn set_memory_range(&mut self, range_begin: FrameId, range_end: FrameId) {
         self.begin = range_begin;
-        self.end = range_end;
+        self.end = FrameId(range_end.0.min(FRAME_COUNT));
     }
 
-    pub fn get_bit(&self, frame: FrameId) -> bool {
-        let line_index = frame.0 / BITS_PER_MAP_LINE;
-        let bit_index = frame.0 % BITS_PER_MAP_LINE;
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
 
-        (self.alloc_map[line_index] & (1 << bit_index)) != 0
+    fn is_available(&self, memory_type: MemoryType) -> bool {
+        memory_type == MemoryType::EfiConventionalMemory
+            || self.reclaimed_types & (1 << memory_type as u32) != 0
     }
 
-    pub fn set_bit(&mut self, frame: FrameId, allocated: bool) {
-        let line_index = frame.0 / BITS_PER_MAP_LINE;
-        let bit_index = frame.0 % BITS_PER_MAP_LINE;
+    // Only the frames entirely inside a region are freed
+    fn free_region(&mut self, desc: &MemoryDescriptor) -> usize {
+        let start = (desc.physical_start as usize + FrameId::SIZE - 1) / FrameId::SIZE;
+        let end = desc.physical_end() as usize / FrameId::SIZE;
+        let free_frames = self.free_frames;
+        if start < end {
+            self.free(FrameId(start), end - start);
+        }
+        self.free_frames - free_frames
+    }
 
-        if allocated {
-            self.alloc_map[line_index] |= 1 << bit_index;
-        } else {
-            self.alloc_map[line_index] &= !(1 << bit_index);
+    /// Frees the regions of the given types. Returns the number of frames reclaimed.
+    fn reclaim(&mut self, memory_types: &[MemoryType]) -> usize {
+        let mut frames = 0;
+        for memory_type in memory_types {
+            if self.is_available(*memory_type) {
+                continue;
+            }
+            for desc in memory_descriptors() {
+                if desc.memory_type == *memory_type {
+                    frames += self.free_region(desc);
+                }
+            }
+            self.reclaimed_types |= 1 << *memory_type as u32;
         }
+        frames
+    }
+
+    /// Counts the free frames in `[start, end)`.
+    fn count_free(&self, start: usize, end: usize) -> usize {
+        let mut free = 0;
+        let mut frame = start;
+        while frame < end {
+            match self.find_free_block(frame) {
+                Some((block, order)) => {
+                    let block_end = block + frames_of_order(order);
+                    free += block_end.min(end) - frame;
+                    frame = block_end;
+                }
+                None => match self.next_free_block(frame, end) {
+                    Some(next) => frame = next,
+                    None => break,
+                },
+            }
+        }
+        free
     }
 }
 
-static MEMORY_MANAGER: SpinMutex<BitmapMemoryManager> = SpinMutex::new(BitmapMemoryManager::new());
+static MEMORY_MANAGER: SpinMutex<BuddyMemoryManager> = SpinMutex::new(BuddyMemoryManager::new());
 
-pub fn memory_manager() -> SpinMutexGuard<'static, BitmapMemoryManager> {
+pub fn memory_manager() -> SpinMutexGuard<'static, BuddyMemoryManager> {
     MEMORY_MANAGER.lock()
 }
 
-pub fn init(mc: &MemoryMap) {
-    let mut mm = MEMORY_MANAGER.try_lock().unwrap();
+/// For interrupt handlers, which must not wait for the code they interrupted.
+pub fn try_memory_manager() -> Option<SpinMutexGuard<'static, BuddyMemoryManager>> {
+    MEMORY_MANAGER.try_lock()
+}
 
-    let mut phys_available_end: usize = 0;
-    let mut iter = mc.buffer;
+// A copy of the map init was given. The descriptors only hold physical addresses.
+struct MemoryDescriptors(ArrayVec<MemoryDescriptor, MAX_MEMORY_DESCRIPTORS>);
+unsafe impl Send for MemoryDescriptors {}
+unsafe impl Sync for MemoryDescriptors {}
 
-    while iter < unsafe { mc.buffer.add(mc.map_size as usize) } {
-        let desc = unsafe { *(iter as *const MemoryDescriptor) };
-        let phys_start = desc.physical_start as usize;
-        let phys_end = desc.physical_end() as usize;
+static MEMORY_DESCRIPTORS: OnceCell<MemoryDescriptors> = OnceCell::uninit();
+
+/// The UEFI memory map as parsed by init.
+pub fn memory_descriptors() -> &'static [MemoryDescriptor] {
+    MEMORY_DESCRIPTORS
+        .try_get()
+        .map_or(&[], |descriptors| descriptors.0.as_slice())
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameCounts {
+    pub total: usize,
+    /// Frames the allocator can hand out
+    pub free: usize,
+    /// Frames the allocator never received
+    pub reserved: usize,
+}
+
+impl FrameCounts {
+    pub fn allocated(&self) -> usize {
+        self.total - self.free - self.reserved
+    }
+
+    fn add(&mut self, other: &FrameCounts) {
+        self.total += other.total;
+        self.free += other.free;
+        self.reserved += other.reserved;
+    }
+}
+
+impl fmt::Display for FrameCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kib = |frames: usize| frames * FrameId::SIZE / 1024;
+        write!(
+            f,
+            "total {} KiB, free {} KiB, allocated {} KiB, reserved {} KiB",
+            kib(self.total),
+            kib(self.free),
+            kib(self.allocated()),
+            kib(self.reserved)
+        )
+    }
+}
+
+/// Frame counts per memory type, in the order the types appear in the map.
+#[derive(Debug, Clone)]
+pub struct MemoryStats {
+    counts: ArrayVec<(MemoryType, FrameCounts), MEMORY_TYPE_COUNT>,
+}
+
+impl MemoryStats {
+    pub fn get(&self, memory_type: MemoryType) -> FrameCounts {
+        self.counts
+            .iter()
+            .find(|(t, _)| *t == memory_type)
+            .map_or(FrameCounts::default(), |(_, counts)| *counts)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(MemoryType, FrameCounts)> {
+        self.counts.iter()
+    }
 
-        if phys_available_end < phys_start {
-            mm.mark_allocated_in_bytes(
-                &FrameId::from_physical_address(x86_64::PhysAddr::new(phys_available_end as u64)),
-                phys_start - phys_available_end,
-            )
+    pub fn total(&self) -> FrameCounts {
+        let mut total = FrameCounts::default();
+        for (_, counts) in &self.counts {
+            total.add(counts);
         }
+        total
+    }
+}
 
-        if desc.memory_type == MemoryType::EfiBootServicesCode
-            || desc.memory_type == MemoryType::EfiBootServicesData
-            || desc.memory_type == MemoryType::EfiConventionalMemory
-        {
-            phys_available_end = phys_end;
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (memory_type, counts) in &self.counts {
+            writeln!(f, "{:?}: {}", memory_type, counts)?;
+        }
+        writeln!(f, "Total: {}", self.total())
+    }
+}
+
+/// Counts the frames of each memory type in the map, and how many of them are free now.
+pub fn stats() -> MemoryStats {
+    let mm = memory_manager();
+    let mut stats = MemoryStats {
+        counts: ArrayVec::new(),
+    };
+    for desc in memory_descriptors() {
+        let start = desc.physical_start as usize / FrameId::SIZE;
+        let end = start + desc.number_of_pages as usize;
+        let managed_start = start.max(mm.begin.0);
+        let managed_end = end.min(mm.end.0).max(managed_start);
+
+        let counts = if mm.is_available(desc.memory_type) {
+            FrameCounts {
+                total: end - start,
+                free: mm.count_free(managed_start, managed_end),
+                reserved: (end - start) - (managed_end - managed_start),
+            }
         } else {
-            mm.mark_allocated_in_bytes(
-                &FrameId::from_physical_address(x86_64::PhysAddr::new(phys_start as u64)),
-                phys_end - phys_start,
-            )
+            FrameCounts {
+                total: end - start,
+                free: 0,
+                reserved: end - start,
+            }
+        };
+
+        match stats
+            .counts
+            .iter_mut()
+            .find(|(t, _)| *t == desc.memory_type)
+        {
+            Some((_, c)) => c.add(&counts),
+            None => stats.counts.push((desc.memory_type, counts)),
         }
+    }
+    stats
+}
 
+/// Logs the memory map as parsed by init. Only visible at the debug level.
+pub fn print_memory_map() {
+    for desc in memory_descriptors() {
+        log!(LogLevel::Debug, "{}\n", desc);
+    }
+}
+
+// Regions which are freed later by the reclaim functions
+fn is_reclaimable(memory_type: MemoryType) -> bool {
+    memory_type == MemoryType::EfiConventionalMemory
+        || memory_type == MemoryType::EfiBootServicesCode
+        || memory_type == MemoryType::EfiBootServicesData
+        || memory_type == MemoryType::EfiACPIReclaimMemory
+}
+
+fn log_reclaimed(name: &str, frames: usize) {
+    log!(
+        LogLevel::Info,
+        "Reclaimed {} KiB of {} memory\n",
+        frames * FrameId::SIZE / 1024,
+        name
+    );
+}
+
+/// Frees the memory used by the UEFI boot services. Call it once nothing the loader
+/// passed, like its stack or the memory map, is used anymore.
+pub fn reclaim_boot_services() -> usize {
+    let frames = memory_manager().reclaim(&[
+        MemoryType::EfiBootServicesCode,
+        MemoryType::EfiBootServicesData,
+    ]);
+    log_reclaimed("boot services", frames);
+    frames
+}
+
+/// Frees the memory holding the ACPI tables. Call it after acpi::init has copied
+/// what it needs out of them.
+pub fn reclaim_acpi_memory() -> usize {
+    let frames = memory_manager().reclaim(&[MemoryType::EfiACPIReclaimMemory]);
+    log_reclaimed("ACPI reclaim", frames);
+    frames
+}
+
+// Calls `f` for each descriptor in the UEFI memory map
+fn for_each_descriptor(mc: &MemoryMap, mut f: impl FnMut(&MemoryDescriptor)) {
+    let mut iter = mc.buffer;
+    while iter < unsafe { mc.buffer.add(mc.map_size as usize) } {
+        let desc = unsafe { *(iter as *const MemoryDescriptor) };
+        f(&desc);
         iter = unsafe { iter.add(mc.descriptor_size as usize) };
     }
+}
+
+pub fn init(mc: &MemoryMap) {
+    let mut mm = MEMORY_MANAGER.try_lock().unwrap();
+
+    let mut descriptors = ArrayVec::new();
+    for_each_descriptor(mc, |desc| {
+        if descriptors.try_push(*desc).is_err() {
+            log!(LogLevel::Warn, "Memory map entry not recorded: {}\n", desc);
+        }
+    });
+    MEMORY_DESCRIPTORS.init_once(|| MemoryDescriptors(descriptors));
+
+    // Every frame starts allocated. Only conventional memory is freed now, the
+    // other reclaimable regions are kept until their owners are done with them.
+    // Free frames hold the links of the free lists, so they must be identity mapped
+    let managed_limit = MAX_PHYSICAL_MEMORY_BYTES.min(paging::IDENTITY_MAPPED_BYTES as usize);
+    let mut phys_available_end: usize = 0;
+    let mut unmanaged_bytes: usize = 0;
+    for_each_descriptor(mc, |desc| {
+        if is_reclaimable(desc.memory_type) {
+            let end = desc.physical_end() as usize;
+            phys_available_end = phys_available_end.max(end.min(managed_limit));
+            unmanaged_bytes +=
+                end.saturating_sub((desc.physical_start as usize).max(managed_limit));
+        }
+    });
+    if unmanaged_bytes > 0 {
+        log!(
+            LogLevel::Warn,
+            "Memory above {} GiB is not used: {} MiB\n",
+            managed_limit >> 30,
+            unmanaged_bytes >> 20
+        );
+    }
     mm.set_memory_range(
         FrameId::MIN,
         FrameId::from_physical_address(x86_64::PhysAddr::new(phys_available_end as u64)),
     );
+
+    for_each_descriptor(mc, |desc| {
+        if mm.is_available(desc.memory_type) {
+            mm.free_region(desc);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::alloc::{alloc_zeroed, Layout};
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use core::ptr;
+
+    const TEST_FRAMES: usize = 64;
+
+    // A manager of its own over frames borrowed from the global one. It is built on
+    // the heap, since its map does not fit on the stack.
+    struct Sandbox {
+        manager: Box<BuddyMemoryManager>,
+        start: usize,
+    }
+
+    impl Sandbox {
+        fn new() -> Self {
+            let start = memory_manager().allocate(TEST_FRAMES).unwrap().0;
+            let manager = unsafe {
+                let manager =
+                    alloc_zeroed(Layout::new::<BuddyMemoryManager>()) as *mut BuddyMemoryManager;
+                assert!(!manager.is_null());
+                ptr::addr_of_mut!((*manager).free_lists).write([NIL; ORDER_COUNT]);
+                ptr::addr_of_mut!((*manager).begin).write(FrameId(start));
+                ptr::addr_of_mut!((*manager).end).write(FrameId(start + TEST_FRAMES));
+                Box::from_raw(manager)
+            };
+            let mut sandbox = Self { manager, start };
+            sandbox.manager.free(FrameId(start), TEST_FRAMES);
+            sandbox
+        }
+
+        // (frame relative to start, order) of every free block
+        fn free_blocks(&self) -> Vec<(usize, usize)> {
+            let mut blocks = Vec::new();
+            for order in 0..ORDER_COUNT {
+                let mut frame = self.manager.free_lists[order];
+                while frame != NIL {
+                    blocks.push((frame - self.start, order));
+                    frame = unsafe { (*BuddyMemoryManager::node(frame)).next };
+                }
+            }
+            blocks.sort_unstable();
+            blocks
+        }
+
+        fn count_free(&self, start: usize, end: usize) -> usize {
+            self.manager
+                .count_free(self.start + start, self.start + end)
+        }
+    }
+
+    impl Drop for Sandbox {
+        fn drop(&mut self) {
+            memory_manager().free(FrameId(self.start), TEST_FRAMES);
+        }
+    }
+
+    #[test_case]
+    fn splits_and_coalesces() {
+        let mut sandbox = Sandbox::new();
+        assert_eq!(sandbox.free_blocks(), [(0, 6)]);
+
+        let frame = sandbox.manager.allocate(1).unwrap();
+        assert_eq!(frame.0, sandbox.start);
+        assert_eq!(
+            sandbox.free_blocks(),
+            [(1, 0), (2, 1), (4, 2), (8, 3), (16, 4), (32, 5)]
+        );
+
+        sandbox.manager.free(frame, 1);
+        assert_eq!(sandbox.free_blocks(), [(0, 6)]);
+        assert_eq!(sandbox.manager.free_frames(), TEST_FRAMES);
+    }
+
+    #[test_case]
+    fn returns_the_tail_of_odd_sized_allocations() {
+        let mut sandbox = Sandbox::new();
+        let frame = sandbox.manager.allocate(3).unwrap();
+        assert_eq!(sandbox.manager.free_frames(), TEST_FRAMES - 3);
+        assert_eq!(
+            sandbox.free_blocks(),
+            [(3, 0), (4, 2), (8, 3), (16, 4), (32, 5)]
+        );
+        sandbox.manager.free(frame, 3);
+        assert_eq!(sandbox.free_blocks(), [(0, 6)]);
+    }
+
+    #[test_case]
+    fn ignores_frames_freed_twice() {
+        let mut sandbox = Sandbox::new();
+        let start = sandbox.start;
+        let frame = sandbox.manager.allocate(8).unwrap();
+        sandbox.manager.free(FrameId(frame.0), 8);
+        sandbox.manager.free(FrameId(frame.0), 8);
+        assert_eq!(sandbox.free_blocks(), [(0, 6)]);
+
+        // Only the half which is still allocated is freed
+        let frame = sandbox.manager.allocate(8).unwrap();
+        sandbox.manager.free(FrameId(frame.0 + 4), 4);
+        sandbox.manager.free(FrameId(frame.0), 8);
+        assert_eq!(sandbox.free_blocks(), [(0, 6)]);
+        assert_eq!(sandbox.manager.free_frames(), TEST_FRAMES);
+        assert_eq!(frame.0, start);
+    }
+
+    #[test_case]
+    fn marks_part_of_a_block_allocated() {
+        let mut sandbox = Sandbox::new();
+        let start = sandbox.start;
+        sandbox.manager.mark_allocated(&FrameId(start + 5), 3);
+        assert_eq!(sandbox.manager.free_frames(), TEST_FRAMES - 3);
+        assert_eq!(sandbox.count_free(0, TEST_FRAMES), TEST_FRAMES - 3);
+        assert_eq!(sandbox.count_free(5, 8), 0);
+        assert_eq!(sandbox.count_free(4, 9), 2);
+
+        sandbox.manager.free(FrameId(start + 5), 3);
+        assert_eq!(sandbox.free_blocks(), [(0, 6)]);
+    }
+
+    #[test_case]
+    fn counts_free_frames_across_allocated_runs() {
+        let mut sandbox = Sandbox::new();
+        let first = sandbox.manager.allocate(32).unwrap();
+        let single = sandbox.manager.allocate(1).unwrap();
+        assert_eq!(single.0, sandbox.start + 32);
+        assert_eq!(sandbox.count_free(0, 32), 0);
+        assert_eq!(sandbox.count_free(16, 48), 15);
+        assert_eq!(sandbox.count_free(0, TEST_FRAMES), TEST_FRAMES - 33);
+
+        sandbox.manager.free(first, 32);
+        sandbox.manager.free(single, 1);
+        assert_eq!(sandbox.count_free(0, TEST_FRAMES), TEST_FRAMES);
+    }
 }
@@ -1,9 +1,10 @@
-use x86_64::structures::paging::PageSize;
-
 const PAGE_SIZE_4K: u64 = 4096;
 const PAGE_SIZE_2M: u64 = 512 * PAGE_SIZE_4K;
 const PAGE_SIZE_1G: u64 = 512 * PAGE_SIZE_2M;
 
+/// Physical memory below this is identity mapped by init.
+pub const IDENTITY_MAPPED_BYTES: u64 = 64 * PAGE_SIZE_1G;
+
 #[repr(align(4096))]
 struct Pml4Table([u64; 512]);
 
@@ -32,7 +33,7 @@ pub fn init() {
 }
 
 pub fn as_virt_addr(addr: x86_64::PhysAddr) -> Option<x86_64::VirtAddr> {
-    if addr.as_u64() < x86_64::structures::paging::Size1GiB::SIZE * 64 {
+    if addr.as_u64() < IDENTITY_MAPPED_BYTES {
         Some(x86_64::VirtAddr::new(addr.as_u64()))
     } else {
         None
@@ -40,7 +41,7 @@ pub fn as_virt_addr(addr: x86_64::PhysAddr) -> Option<x86_64::VirtAddr> {
 }
 
 pub fn as_phys_addr(addr: x86_64::VirtAddr) -> Option<x86_64::PhysAddr> {
-    if addr.as_u64() < x86_64::structures::paging::Size1GiB::SIZE * 64 {
+    if addr.as_u64() < IDENTITY_MAPPED_BYTES {
         Some(x86_64::PhysAddr::new(addr.as_u64()))
     } else {
         None
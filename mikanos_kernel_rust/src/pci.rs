@@ -1,11 +1,20 @@
-use arrayvec::ArrayVec;
+use alloc::vec::Vec;
 use core::fmt::Formatter;
 use core::ops::Range;
 // To use set_bits and set_bit for numbers
-use crate::log;
 use crate::logger::Level as LogLevel;
+use crate::paging::as_virt_addr;
+use crate::{acpi, log, pci_ids, timer};
+use arrayvec::ArrayVec;
 use bit_field::BitField;
+use volatile::Volatile;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+// Port I/O only reaches the first 256 bytes of the configuration space
+const LEGACY_CONFIG_SPACE_SIZE: u16 = 0x100;
+const EXTENDED_CONFIG_SPACE_SIZE: u16 = 0x1000;
 
 // config_address is 32 bit register
 struct ConfigAddress(u32);
@@ -54,65 +63,409 @@ static CONFIG: Config = Config(spin::Mutex::new(PortSet {
     data: Port::new(0xcfc),
 }));
 
+// Layout of the configuration header, bits 0-6 of the header type
+const HEADER_TYPE_GENERAL: u8 = 0x00;
+const HEADER_TYPE_PCI_BRIDGE: u8 = 0x01;
+const HEADER_TYPE_CARDBUS_BRIDGE: u8 = 0x02;
+
+const REG_COMMAND: u16 = 0x04;
+const REG_BAR0: u16 = 0x10;
+const MAX_BARS: usize = 6;
+
+// Command register
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarKind {
+    Io,
+    Memory32,
+    Memory64,
+}
+
+/// A region decoded by a Base Address Register.
+#[derive(Debug, Clone, Copy)]
+pub struct Bar {
+    pub index: u8,
+    pub kind: BarKind,
+    pub base: u64,
+    pub size: u64,
+    pub prefetchable: bool,
+}
+
+impl Bar {
+    pub fn is_memory(&self) -> bool {
+        self.kind != BarKind::Io
+    }
+
+    /// Returns the virtual address of a memory BAR for MMIO access.
+    pub fn map(&self) -> Result<VirtAddr, ()> {
+        if !self.is_memory() || self.base == 0 {
+            return Err(());
+        }
+        // Memory below 64 GiB is identity mapped, so the whole region must be below it
+        as_virt_addr(PhysAddr::new(self.base + self.size - 1)).ok_or(())?;
+        as_virt_addr(PhysAddr::new(self.base)).ok_or(())
+    }
+}
+
+fn format_size(f: &mut Formatter<'_>, size: u64) -> core::fmt::Result {
+    match size {
+        s if s >= 1 << 30 && s % (1 << 30) == 0 => write!(f, "{}G", s >> 30),
+        s if s >= 1 << 20 && s % (1 << 20) == 0 => write!(f, "{}M", s >> 20),
+        s if s >= 1 << 10 && s % (1 << 10) == 0 => write!(f, "{}K", s >> 10),
+        s => write!(f, "{}", s),
+    }
+}
+
+impl core::fmt::Display for Bar {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self.kind {
+            BarKind::Io => write!(f, "I/O ports at {:04x} [size=", self.base)?,
+            _ => write!(
+                f,
+                "Memory at {:08x} ({}-bit, {}prefetchable) [size=",
+                self.base,
+                if self.kind == BarKind::Memory64 {
+                    64
+                } else {
+                    32
+                },
+                if self.prefetchable { "" } else { "non-" }
+            )?,
+        }
+        format_size(f, self.size)?;
+        write!(f, "]")
+    }
+}
+
+/// Bus numbers behind a PCI-PCI or CardBus bridge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BridgeBuses {
+    pub primary: u8,
+    pub secondary: u8,
+    pub subordinate: u8,
+}
+
 #[derive(Debug, Clone)]
 pub struct Device {
     pub bus: u8,
     pub device: u8,
     pub function: u8,
     pub vendor_id: u16,
+    pub device_id: u16,
+    // base class, sub class, programming interface and revision ID from the top byte
     pub class_code: u32,
+    pub revision: u8,
     pub header_type: u8,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    pub interrupt_line: u8,
+    // 0: none, 1-4: INTA#-INTD#
+    pub interrupt_pin: u8,
+    pub bridge_buses: Option<BridgeBuses>,
+    // Sized once at enumeration, since sizing writes to the BARs
+    bars: ArrayVec<Bar, MAX_BARS>,
 }
 
 impl Device {
-    fn addr(&self, reg_addr: u8) -> ConfigAddress {
-        ConfigAddress::new(self.bus, self.device, self.function, reg_addr)
+    fn read(bus: u8, device: u8, function: u8) -> Self {
+        let id = read_config(bus, device, function, 0x00);
+        let class_code = read_class_code(bus, device, function);
+        let header_type = read_header_type(bus, device, function);
+        let interrupt = read_config(bus, device, function, 0x3c);
+
+        let subsystem = match header_type & 0x7f {
+            HEADER_TYPE_GENERAL => read_config(bus, device, function, 0x2c),
+            HEADER_TYPE_CARDBUS_BRIDGE => read_config(bus, device, function, 0x40),
+            _ => 0,
+        };
+        let bridge_buses = match header_type & 0x7f {
+            HEADER_TYPE_PCI_BRIDGE | HEADER_TYPE_CARDBUS_BRIDGE => {
+                let bus_number = read_bus_number(bus, device, function);
+                Some(BridgeBuses {
+                    primary: bus_number.get_bits(0..8) as u8,
+                    secondary: bus_number.get_bits(8..16) as u8,
+                    subordinate: bus_number.get_bits(16..24) as u8,
+                })
+            }
+            _ => None,
+        };
+
+        let mut dev = Self {
+            bus,
+            device,
+            function,
+            vendor_id: id.get_bits(0..16) as u16,
+            device_id: id.get_bits(16..32) as u16,
+            class_code,
+            revision: class_code.get_bits(0..8) as u8,
+            header_type,
+            subsystem_vendor_id: subsystem.get_bits(0..16) as u16,
+            subsystem_id: subsystem.get_bits(16..32) as u16,
+            interrupt_line: interrupt.get_bits(0..8) as u8,
+            interrupt_pin: interrupt.get_bits(8..16) as u8,
+            bridge_buses,
+            bars: ArrayVec::new(),
+        };
+        dev.bars = dev.probe_bars();
+        dev
     }
 
-    pub fn read_bar(&self, bar_index: u8) -> Result<u64, ()> {
-        if bar_index >= 6 {
-            return Err(());
+    pub fn base_class(&self) -> u8 {
+        self.class_code.get_bits(24..32) as u8
+    }
+
+    pub fn sub_class(&self) -> u8 {
+        self.class_code.get_bits(16..24) as u8
+    }
+
+    pub fn prog_if(&self) -> u8 {
+        self.class_code.get_bits(8..16) as u8
+    }
+
+    pub fn is_class(&self, base_class: u8, sub_class: u8, prog_if: u8) -> bool {
+        self.base_class() == base_class
+            && self.sub_class() == sub_class
+            && self.prog_if() == prog_if
+    }
+
+    pub fn bar_count(&self) -> u8 {
+        match self.header_type & 0x7f {
+            HEADER_TYPE_GENERAL => 6,
+            HEADER_TYPE_PCI_BRIDGE => 2,
+            _ => 0,
         }
+    }
 
-        let addr = 0x10 + 4 * bar_index;
-        let bar = read_data(self.addr(addr));
+    // Writes all ones and reads back which address bits the device decodes
+    fn size_register(&self, reg_addr: u16) -> u32 {
+        let original = self.read_conf_reg(reg_addr);
+        self.write_conf_reg(reg_addr, 0xffff_ffff);
+        let mask = self.read_conf_reg(reg_addr);
+        self.write_conf_reg(reg_addr, original);
+        mask
+    }
 
-        // 32 bit address
-        if (bar & 4) == 0 {
-            return Ok(u64::from(bar));
+    /// Probes a BAR. Returns None for an unimplemented BAR or the upper half of a 64 bit BAR.
+    fn probe_bar(&self, index: u8) -> Result<Option<Bar>, ()> {
+        if index >= self.bar_count() {
+            return Err(());
         }
 
-        // 64 bit address
-        if bar_index >= 5 {
+        let reg_addr = REG_BAR0 + 4 * u16::from(index);
+        let low = self.read_conf_reg(reg_addr);
+        let kind = if low.get_bit(0) {
+            BarKind::Io
+        } else {
+            match low.get_bits(1..3) {
+                0b00 => BarKind::Memory32,
+                0b10 => BarKind::Memory64,
+                _ => return Err(()),
+            }
+        };
+        if kind == BarKind::Memory64 && index + 1 >= self.bar_count() {
             return Err(());
         }
 
-        let bar_upper = read_data(self.addr(addr + 4));
-        Ok(u64::from(bar) | u64::from(bar_upper) << 32)
+        // Decoding is disabled while the BAR temporarily holds all ones
+        let command = self.read_conf_reg(REG_COMMAND) & 0xffff;
+        let (mask, high, high_mask) = without_interrupts(|| {
+            self.write_conf_reg(
+                REG_COMMAND,
+                command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+            );
+            let mask = self.size_register(reg_addr);
+            let (high, high_mask) = if kind == BarKind::Memory64 {
+                (
+                    self.read_conf_reg(reg_addr + 4),
+                    self.size_register(reg_addr + 4),
+                )
+            } else {
+                (0, 0)
+            };
+            self.write_conf_reg(REG_COMMAND, command);
+            (mask, high, high_mask)
+        });
+
+        let (base, mask) = match kind {
+            BarKind::Io => (u64::from(low & !0x3), u64::from(mask & !0x3)),
+            BarKind::Memory32 => (u64::from(low & !0xf), u64::from(mask & !0xf)),
+            BarKind::Memory64 => (
+                u64::from(high) << 32 | u64::from(low & !0xf),
+                u64::from(high_mask) << 32 | u64::from(mask & !0xf),
+            ),
+        };
+        if mask == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(Bar {
+            index,
+            kind,
+            base,
+            // The lowest decoded address bit gives the size
+            size: 1 << mask.trailing_zeros(),
+            prefetchable: kind != BarKind::Io && low.get_bit(3),
+        }))
+    }
+
+    fn probe_bars(&self) -> ArrayVec<Bar, MAX_BARS> {
+        let mut bars = ArrayVec::new();
+        let mut index = 0;
+        while index < self.bar_count() {
+            match self.probe_bar(index) {
+                Ok(Some(bar)) => {
+                    index += if bar.kind == BarKind::Memory64 { 2 } else { 1 };
+                    bars.push(bar);
+                }
+                _ => index += 1,
+            }
+        }
+        bars
+    }
+
+    /// The implemented BARs, as sized at enumeration.
+    pub fn bars(&self) -> &[Bar] {
+        &self.bars
+    }
+
+    pub fn bar(&self, index: u8) -> Result<Bar, ()> {
+        self.bars
+            .iter()
+            .find(|bar| bar.index == index)
+            .copied()
+            .ok_or(())
+    }
+
+    /// Reads a 32 bit register. Registers beyond 0xff read as all ones without ECAM.
+    pub fn read_conf_reg(&self, reg_addr: u16) -> u32 {
+        read_config(self.bus, self.device, self.function, reg_addr)
     }
 
-    pub fn read_conf_reg(&self, reg_addr: u8) -> u32 {
-        let addr = ConfigAddress::new(self.bus, self.device, self.function, reg_addr);
-        read_data(addr)
+    /// Writes a 32 bit register. Registers beyond 0xff are ignored without ECAM.
+    pub fn write_conf_reg(&self, reg_addr: u16, value: u32) {
+        write_config(self.bus, self.device, self.function, reg_addr, value)
     }
 
-    pub fn write_conf_reg(&self, reg_addr: u8, value: u32) {
-        let addr = ConfigAddress::new(self.bus, self.device, self.function, reg_addr);
-        write_data(addr, value)
+    pub fn config_space_size(&self) -> u16 {
+        if ecam_register(self.bus, self.device, self.function, 0).is_some() {
+            EXTENDED_CONFIG_SPACE_SIZE
+        } else {
+            LEGACY_CONFIG_SPACE_SIZE
+        }
+    }
+}
+
+#[cfg(test)]
+impl Device {
+    /// A device which is not on any bus, for tests of code matching devices.
+    pub fn fake(vendor_id: u16, device_id: u16, class_code: u32) -> Self {
+        Self {
+            bus: 0xff,
+            device: 0,
+            function: 0,
+            vendor_id,
+            device_id,
+            class_code,
+            revision: class_code.get_bits(0..8) as u8,
+            header_type: HEADER_TYPE_GENERAL,
+            subsystem_vendor_id: 0,
+            subsystem_id: 0,
+            interrupt_line: 0,
+            interrupt_pin: 0,
+            bridge_buses: None,
+            bars: ArrayVec::new(),
+        }
     }
 }
 
+// Formats like `lspci -nn`
 impl core::fmt::Display for Device {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        write!(
-            f,
-            "{:02x}.{:02x}.{:02x} vend={:04x}, class={:08x}, head={:02x}",
-            self.bus, self.device, self.function, self.vendor_id, self.class_code, self.header_type
-        )
+        write!(f, "{:02x}:{:02x}.{} ", self.bus, self.device, self.function)?;
+        match pci_ids::subclass_name(self.base_class(), self.sub_class())
+            .or_else(|| pci_ids::class_name(self.base_class()))
+        {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "Class")?,
+        }
+        write!(f, " [{:02x}{:02x}]: ", self.base_class(), self.sub_class())?;
+        match pci_ids::vendor_name(self.vendor_id) {
+            Some(name) => write!(f, "{} ", name)?,
+            None => write!(f, "Vendor {:04x} ", self.vendor_id)?,
+        }
+        match pci_ids::device_name(self.vendor_id, self.device_id) {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "Device {:04x}", self.device_id)?,
+        }
+        write!(f, " [{:04x}:{:04x}]", self.vendor_id, self.device_id)?;
+        if self.revision != 0 {
+            write!(f, " (rev {:02x})", self.revision)?;
+        }
+        if self.prog_if() != 0 {
+            write!(f, " (prog-if {:02x}", self.prog_if())?;
+            if let Some(name) =
+                pci_ids::prog_if_name(self.base_class(), self.sub_class(), self.prog_if())
+            {
+                write!(f, " [{}]", name)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+/// The indented detail lines of `lspci -v` for a device.
+pub struct DeviceDetails<'a>(&'a Device);
+
+impl Device {
+    pub fn details(&self) -> DeviceDetails<'_> {
+        DeviceDetails(self)
+    }
+}
+
+impl core::fmt::Display for DeviceDetails<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let device = self.0;
+        if device.subsystem_vendor_id != 0 && device.subsystem_vendor_id != 0xffff {
+            writeln!(
+                f,
+                "\tSubsystem: {} [{:04x}:{:04x}]",
+                pci_ids::vendor_name(device.subsystem_vendor_id).unwrap_or("Vendor"),
+                device.subsystem_vendor_id,
+                device.subsystem_id
+            )?;
+        }
+        if let Some(buses) = device.bridge_buses {
+            writeln!(
+                f,
+                "\tBus: primary={:02x}, secondary={:02x}, subordinate={:02x}",
+                buses.primary, buses.secondary, buses.subordinate
+            )?;
+        }
+        if (1..=4).contains(&device.interrupt_pin) {
+            writeln!(
+                f,
+                "\tInterrupt: pin {} routed to IRQ {}",
+                (b'A' + device.interrupt_pin - 1) as char,
+                device.interrupt_line
+            )?;
+        }
+        for bar in device.bars() {
+            writeln!(f, "\t{}", bar)?;
+        }
+        for capability in device.capabilities() {
+            writeln!(f, "\tCapabilities: {}", capability)?;
+        }
+        for capability in device.extended_capabilities() {
+            writeln!(f, "\tCapabilities: {}", capability)?;
+        }
+        Ok(())
     }
 }
 
-pub type Devices = ArrayVec<Device, 32>;
+pub type Devices = Vec<Device>;
 
 fn read_data(addr: ConfigAddress) -> u32 {
     let mut ports = CONFIG.0.lock();
@@ -130,116 +483,174 @@ fn write_data(addr: ConfigAddress, value: u32) {
     }
 }
 
+// Memory mapped configuration register of segment 0 from the ACPI MCFG table
+fn ecam_register(
+    bus: u8,
+    device: u8,
+    function: u8,
+    reg_addr: u16,
+) -> Option<Volatile<&'static mut u32>> {
+    let entry = acpi::tables()?
+        .mcfg
+        .iter()
+        .find(|entry| entry.segment == 0 && (entry.start_bus..=entry.end_bus).contains(&bus))?;
+    let offset = u64::from(bus) << 20
+        | u64::from(device) << 15
+        | u64::from(function) << 12
+        | u64::from(reg_addr);
+    let virt_addr = as_virt_addr(PhysAddr::new(entry.base_address + offset))?;
+    Some(Volatile::new(unsafe {
+        virt_addr.as_mut_ptr::<u32>().as_mut().unwrap()
+    }))
+}
+
+fn read_config(bus: u8, device: u8, function: u8, reg_addr: u16) -> u32 {
+    assert_eq!(reg_addr & 0x3, 0);
+    assert!(reg_addr < EXTENDED_CONFIG_SPACE_SIZE);
+
+    if let Some(reg) = ecam_register(bus, device, function, reg_addr) {
+        return reg.read();
+    }
+    if reg_addr >= LEGACY_CONFIG_SPACE_SIZE {
+        return 0xffff_ffff;
+    }
+    read_data(ConfigAddress::new(bus, device, function, reg_addr as u8))
+}
+
+fn write_config(bus: u8, device: u8, function: u8, reg_addr: u16, value: u32) {
+    assert_eq!(reg_addr & 0x3, 0);
+    assert!(reg_addr < EXTENDED_CONFIG_SPACE_SIZE);
+
+    if let Some(mut reg) = ecam_register(bus, device, function, reg_addr) {
+        reg.write(value);
+        return;
+    }
+    if reg_addr >= LEGACY_CONFIG_SPACE_SIZE {
+        return;
+    }
+    write_data(
+        ConfigAddress::new(bus, device, function, reg_addr as u8),
+        value,
+    )
+}
+
 fn read_vendor_id(bus: u8, device: u8, function: u8) -> u16 {
-    let addr = ConfigAddress::new(bus, device, function, 0x00);
-    (read_data(addr) & 0xffff) as u16
+    (read_config(bus, device, function, 0x00) & 0xffff) as u16
 }
 
 fn read_class_code(bus: u8, device: u8, function: u8) -> u32 {
-    let addr = ConfigAddress::new(bus, device, function, 0x08);
-    read_data(addr)
+    read_config(bus, device, function, 0x08)
 }
 
 fn read_header_type(bus: u8, device: u8, function: u8) -> u8 {
-    let addr = ConfigAddress::new(bus, device, function, 0x0c);
-    ((read_data(addr) >> 16) & 0xff) as u8
+    ((read_config(bus, device, function, 0x0c) >> 16) & 0xff) as u8
 }
 
 fn read_bus_number(bus: u8, device: u8, function: u8) -> u32 {
-    let addr = ConfigAddress::new(bus, device, function, 0x18);
-    read_data(addr)
+    read_config(bus, device, function, 0x18)
 }
 
 fn is_single_function_device(header_type: u8) -> bool {
     (header_type & 0x80) == 0
 }
 
-fn scan_function(devices: &mut Devices, bus: u8, device: u8, function: u8) -> Result<(), ()> {
-    let vendor_id = read_vendor_id(bus, device, function);
-    let class_code = read_class_code(bus, device, function);
-    let header_type = read_header_type(bus, device, function);
-
-    devices
-        .try_push(Device {
-            bus,
-            device,
-            function,
-            vendor_id,
-            class_code,
-            header_type,
-        })
-        .map_err(|_| ())?;
+struct BusScan {
+    devices: Devices,
+    // Guards against bridges misconfigured to loop back to an upper bus
+    scanned_buses: [bool; 256],
+}
 
-    let base = ((class_code >> 24) & 0xff) as u8;
-    let sub = ((class_code >> 16) & 0xff) as u8;
+fn scan_function(scan: &mut BusScan, bus: u8, device: u8, function: u8) {
+    let dev = Device::read(bus, device, function);
+    let bridge_buses = dev.bridge_buses;
+    scan.devices.push(dev);
 
-    if base == 0x06 && sub == 0x04 {
-        // standard PCI-PCI bridge
-        let bus_number = read_bus_number(bus, device, function);
-        let secondary_bus = ((bus_number >> 8) & 0xff) as u8;
-        scan_bus(devices, secondary_bus)?;
+    if let Some(buses) = bridge_buses {
+        // An unconfigured bridge has 0 as the secondary bus
+        if buses.secondary != 0 && buses.secondary <= buses.subordinate {
+            scan_bus(scan, buses.secondary);
+        }
     }
-
-    Ok(())
 }
 
-fn scan_device(devices: &mut Devices, bus: u8, device: u8) -> Result<(), ()> {
-    scan_function(devices, bus, device, 0)?;
+fn scan_device(scan: &mut BusScan, bus: u8, device: u8) {
+    scan_function(scan, bus, device, 0);
 
     if is_single_function_device(read_header_type(bus, device, 0)) {
-        return Ok(());
+        return;
     }
 
     for function in 1..8 {
         if read_vendor_id(bus, device, function) == 0xffff {
             continue;
         }
-        scan_function(devices, bus, device, function)?;
+        scan_function(scan, bus, device, function);
     }
-
-    Ok(())
 }
 
-fn scan_bus(devices: &mut Devices, bus: u8) -> Result<(), ()> {
+fn scan_bus(scan: &mut BusScan, bus: u8) {
+    if scan.scanned_buses[usize::from(bus)] {
+        log!(LogLevel::Warn, "PCI: bus {:02x} is already scanned\n", bus);
+        return;
+    }
+    scan.scanned_buses[usize::from(bus)] = true;
+
     for device in 0..32 {
         if read_vendor_id(bus, device, 0) == 0xffff {
             continue;
         }
 
-        scan_device(devices, bus, device)?;
+        scan_device(scan, bus, device);
     }
-
-    Ok(())
 }
 
 pub fn scan_all_bus() -> Result<Devices, ()> {
-    let mut devices = Devices::new();
+    let mut scan = BusScan {
+        devices: Devices::new(),
+        scanned_buses: [false; 256],
+    };
+
+    for entry in acpi::tables().iter().flat_map(|tables| &tables.mcfg) {
+        log!(
+            LogLevel::Info,
+            "PCI: ECAM at {:08x}, segment {}, bus {:02x}-{:02x}\n",
+            entry.base_address,
+            entry.segment,
+            entry.start_bus,
+            entry.end_bus
+        );
+    }
 
+    if read_vendor_id(0, 0, 0) == 0xffff {
+        return Err(());
+    }
     let header_type = read_header_type(0, 0, 0);
     if is_single_function_device(header_type) {
-        scan_bus(&mut devices, 0)?;
-        return Ok(devices);
-    }
+        scan_bus(&mut scan, 0);
+    } else {
+        // Each function of the host bridge at 00:00.0 owns the bus with its number
+        for function in 0..8 {
+            if read_vendor_id(0, 0, function) == 0xffff {
+                continue;
+            }
 
-    for bus in 1..8 {
-        if read_vendor_id(0, 0, bus) == 0xffff {
-            continue;
+            scan_bus(&mut scan, function);
         }
-
-        scan_bus(&mut devices, bus)?;
     }
 
-    Ok(devices)
+    log!(
+        LogLevel::Info,
+        "PCI: {} devices found\n",
+        scan.devices.len()
+    );
+    Ok(scan.devices)
 }
 
-pub fn switch_ehci_to_xhci(xhc_device: &Device, devices: &Devices) {
+pub fn switch_ehci_to_xhci(xhc_device: &Device, devices: &[Device]) {
     let mut found_echi_device = false;
     for device in devices {
         // Find EHCI device
-        if ((device.class_code >> 24) & 0xff) as u8 == 0x0c
-            && ((device.class_code >> 16) & 0xff) as u8 == 0x03
-            && ((device.class_code >> 8) & 0xff) as u8 == 0x20
-        {
+        if device.is_class(0x0c, 0x03, 0x20) {
             found_echi_device = true;
         }
     }
@@ -360,11 +771,484 @@ struct MsiCapability {
     pending_bits: u32,
 }
 
-fn read_capability_header(device: &Device, cap_addr: u8) -> CapabilityHeader {
+fn read_capability_header(device: &Device, cap_addr: u16) -> CapabilityHeader {
     CapabilityHeader::from(device.read_conf_reg(cap_addr))
 }
 
-fn read_msi_capability(device: &Device, cap_addr: u8) -> MsiCapability {
+const CAPABILITY_POWER_MANAGEMENT: u8 = 0x01;
+const CAPABILITY_VENDOR_SPECIFIC: u8 = 0x09;
+const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+const CAPABILITY_ADVANCED_FEATURES: u8 = 0x13;
+
+// Status register
+const STATUS_CAPABILITIES_LIST: usize = 20;
+const REG_CAPABILITIES_POINTER: u16 = 0x34;
+// Bounds the walk in case a broken device links the list into a loop
+const MAX_CAPABILITIES: usize = 48;
+const MAX_EXTENDED_CAPABILITIES: usize = 960;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PowerState {
+    D0 = 0,
+    D1 = 1,
+    D2 = 2,
+    D3Hot = 3,
+}
+
+impl From<u32> for PowerState {
+    fn from(value: u32) -> Self {
+        match value & 0x3 {
+            0 => Self::D0,
+            1 => Self::D1,
+            2 => Self::D2,
+            _ => Self::D3Hot,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PowerManagementInfo {
+    pub version: u8,
+    pub d1_supported: bool,
+    pub d2_supported: bool,
+    // States from which PME# can be asserted, bit 0 for D0 up to bit 4 for D3cold
+    pub pme_support: u8,
+    pub power_state: PowerState,
+    // The device keeps its configuration across D3hot to D0
+    pub no_soft_reset: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MsiInfo {
+    pub enabled: bool,
+    // log2 of the number of vectors
+    pub multi_message_capable: u8,
+    pub addr_64: bool,
+    pub per_vector_mask: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MsixInfo {
+    pub enabled: bool,
+    pub function_masked: bool,
+    pub table_size: u16,
+    pub table_bar: u8,
+    pub table_offset: u32,
+    pub pba_bar: u8,
+    pub pba_offset: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcieDeviceType {
+    Endpoint,
+    LegacyEndpoint,
+    RootPort,
+    UpstreamPort,
+    DownstreamPort,
+    PcieToPciBridge,
+    PciToPcieBridge,
+    RootComplexIntegratedEndpoint,
+    RootComplexEventCollector,
+    Other(u8),
+}
+
+impl From<u8> for PcieDeviceType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Endpoint,
+            1 => Self::LegacyEndpoint,
+            4 => Self::RootPort,
+            5 => Self::UpstreamPort,
+            6 => Self::DownstreamPort,
+            7 => Self::PcieToPciBridge,
+            8 => Self::PciToPcieBridge,
+            9 => Self::RootComplexIntegratedEndpoint,
+            10 => Self::RootComplexEventCollector,
+            _ => Self::Other(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PciExpressInfo {
+    pub version: u8,
+    pub device_type: PcieDeviceType,
+    pub slot_implemented: bool,
+    pub max_payload_size: u16,
+    // 1: 2.5 GT/s, 2: 5 GT/s, 3: 8 GT/s, ...
+    pub link_speed: u8,
+    pub link_width: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AdvancedFeaturesInfo {
+    pub transactions_pending: bool,
+    pub function_level_reset: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Capability {
+    PowerManagement(PowerManagementInfo),
+    Msi(MsiInfo),
+    MsiX(MsixInfo),
+    PciExpress(PciExpressInfo),
+    VendorSpecific { length: u8 },
+    AdvancedFeatures(AdvancedFeaturesInfo),
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PciCapability {
+    pub offset: u16,
+    pub id: u8,
+    pub capability: Capability,
+}
+
+impl PciCapability {
+    fn read(device: &Device, offset: u16) -> (Self, u16) {
+        let header = read_capability_header(device, offset);
+        let capability = match header.cap_id {
+            CAPABILITY_POWER_MANAGEMENT => {
+                let control = device.read_conf_reg(offset + 4);
+                Capability::PowerManagement(PowerManagementInfo {
+                    version: header.cap.get_bits(0..3) as u8,
+                    d1_supported: header.cap.get_bit(9),
+                    d2_supported: header.cap.get_bit(10),
+                    pme_support: header.cap.get_bits(11..16) as u8,
+                    power_state: PowerState::from(control),
+                    no_soft_reset: control.get_bit(3),
+                })
+            }
+            CAPABILITY_MSI => Capability::Msi(MsiInfo {
+                enabled: header.cap.get_bit(CapabilityHeader::BIT_MSI_ENABLE),
+                multi_message_capable: header.multi_msg_capable(),
+                addr_64: header.addr_64_capable(),
+                per_vector_mask: header.per_vector_mask_capable(),
+            }),
+            CAPABILITY_MSIX => {
+                let table = device.read_conf_reg(offset + 4);
+                let pba = device.read_conf_reg(offset + 8);
+                Capability::MsiX(MsixInfo {
+                    enabled: header.cap.get_bit(MSIX_BIT_ENABLE),
+                    function_masked: header.cap.get_bit(MSIX_BIT_FUNCTION_MASK),
+                    table_size: header.cap.get_bits(MSIX_BITS_TABLE_SIZE) + 1,
+                    table_bar: table.get_bits(0..3) as u8,
+                    table_offset: table & !0x7,
+                    pba_bar: pba.get_bits(0..3) as u8,
+                    pba_offset: pba & !0x7,
+                })
+            }
+            CAPABILITY_PCI_EXPRESS => {
+                let device_capabilities = device.read_conf_reg(offset + 4);
+                let link_status = device.read_conf_reg(offset + 0x10) >> 16;
+                Capability::PciExpress(PciExpressInfo {
+                    version: header.cap.get_bits(0..4) as u8,
+                    device_type: PcieDeviceType::from(header.cap.get_bits(4..8) as u8),
+                    slot_implemented: header.cap.get_bit(8),
+                    max_payload_size: 128 << device_capabilities.get_bits(0..3),
+                    link_speed: link_status.get_bits(0..4) as u8,
+                    link_width: link_status.get_bits(4..10) as u8,
+                })
+            }
+            CAPABILITY_VENDOR_SPECIFIC => Capability::VendorSpecific {
+                length: header.cap.get_bits(0..8) as u8,
+            },
+            CAPABILITY_ADVANCED_FEATURES => Capability::AdvancedFeatures(AdvancedFeaturesInfo {
+                transactions_pending: header.cap.get_bit(8),
+                function_level_reset: header.cap.get_bit(9),
+            }),
+            id => Capability::Other(id),
+        };
+
+        let capability = Self {
+            offset,
+            id: header.cap_id,
+            capability,
+        };
+        (capability, u16::from(header.next_ptr & 0xfc))
+    }
+}
+
+/// Iterates the capability list in the first 256 bytes of the configuration space.
+pub struct Capabilities<'a> {
+    device: &'a Device,
+    next: u16,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities<'_> {
+    type Item = PciCapability;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == 0 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let (capability, next) = PciCapability::read(self.device, self.next);
+        self.next = next;
+        Some(capability)
+    }
+}
+
+/// A PCI Express extended capability such as AER or SR-IOV.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtendedCapability {
+    pub offset: u16,
+    pub id: u16,
+    pub version: u8,
+}
+
+/// Iterates the extended capabilities from 0x100, which are reachable only through ECAM.
+pub struct ExtendedCapabilities<'a> {
+    device: &'a Device,
+    next: u16,
+    remaining: usize,
+}
+
+impl Iterator for ExtendedCapabilities<'_> {
+    type Item = ExtendedCapability;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next < LEGACY_CONFIG_SPACE_SIZE || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let header = self.device.read_conf_reg(self.next);
+        if header == 0 || header == 0xffff_ffff {
+            return None;
+        }
+        let capability = ExtendedCapability {
+            offset: self.next,
+            id: header.get_bits(0..16) as u16,
+            version: header.get_bits(16..20) as u8,
+        };
+        self.next = header.get_bits(20..32) as u16 & 0xffc;
+        Some(capability)
+    }
+}
+
+fn plus_minus(value: bool) -> char {
+    if value {
+        '+'
+    } else {
+        '-'
+    }
+}
+
+impl core::fmt::Display for PcieDeviceType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Endpoint => write!(f, "Endpoint"),
+            Self::LegacyEndpoint => write!(f, "Legacy Endpoint"),
+            Self::RootPort => write!(f, "Root Port"),
+            Self::UpstreamPort => write!(f, "Upstream Port"),
+            Self::DownstreamPort => write!(f, "Downstream Port"),
+            Self::PcieToPciBridge => write!(f, "PCI-Express to PCI/PCI-X Bridge"),
+            Self::PciToPcieBridge => write!(f, "PCI/PCI-X to PCI-Express Bridge"),
+            Self::RootComplexIntegratedEndpoint => write!(f, "Root Complex Integrated Endpoint"),
+            Self::RootComplexEventCollector => write!(f, "Root Complex Event Collector"),
+            Self::Other(value) => write!(f, "Unknown type {}", value),
+        }
+    }
+}
+
+// Formats like the capability lines of `lspci -v`
+impl core::fmt::Display for PciCapability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "[{:02x}] ", self.offset)?;
+        match self.capability {
+            Capability::PowerManagement(pm) => write!(
+                f,
+                "Power Management version {}, D1{} D2{}, state {:?}",
+                pm.version,
+                plus_minus(pm.d1_supported),
+                plus_minus(pm.d2_supported),
+                pm.power_state
+            ),
+            Capability::Msi(msi) => write!(
+                f,
+                "MSI: Enable{} Count={} Maskable{} 64bit{}",
+                plus_minus(msi.enabled),
+                1 << msi.multi_message_capable,
+                plus_minus(msi.per_vector_mask),
+                plus_minus(msi.addr_64)
+            ),
+            Capability::MsiX(msix) => write!(
+                f,
+                "MSI-X: Enable{} Count={} Masked{}, Vector table: BAR={} offset={:08x}, PBA: BAR={} offset={:08x}",
+                plus_minus(msix.enabled),
+                msix.table_size,
+                plus_minus(msix.function_masked),
+                msix.table_bar,
+                msix.table_offset,
+                msix.pba_bar,
+                msix.pba_offset
+            ),
+            Capability::PciExpress(pcie) => {
+                write!(
+                    f,
+                    "Express (v{}) {}, MaxPayload {} bytes",
+                    pcie.version, pcie.device_type, pcie.max_payload_size
+                )?;
+                // 2.5, 5, 8, 16, 32 GT/s
+                match pcie.link_speed {
+                    0 => Ok(()),
+                    1 => write!(f, ", Link 2.5GT/s x{}", pcie.link_width),
+                    2 => write!(f, ", Link 5GT/s x{}", pcie.link_width),
+                    speed => write!(
+                        f,
+                        ", Link {}GT/s x{}",
+                        8 << (speed - 3),
+                        pcie.link_width
+                    ),
+                }
+            }
+            Capability::VendorSpecific { length } => {
+                write!(f, "Vendor Specific Information: Len={:02x}", length)
+            }
+            Capability::AdvancedFeatures(af) => write!(
+                f,
+                "PCI Advanced Features: TP{} FLR{}",
+                plus_minus(af.transactions_pending),
+                plus_minus(af.function_level_reset)
+            ),
+            Capability::Other(id) => write!(f, "Capability ID {:02x}", id),
+        }
+    }
+}
+
+impl ExtendedCapability {
+    pub fn name(&self) -> Option<&'static str> {
+        Some(match self.id {
+            0x0001 => "Advanced Error Reporting",
+            0x0002 => "Virtual Channel",
+            0x0003 => "Device Serial Number",
+            0x0004 => "Power Budgeting",
+            0x000b => "Vendor Specific Information",
+            0x000d => "Access Control Services",
+            0x000e => "Alternative Routing-ID Interpretation (ARI)",
+            0x000f => "Address Translation Service (ATS)",
+            0x0010 => "Single Root I/O Virtualization (SR-IOV)",
+            0x0015 => "Physical Resizable BAR",
+            0x0018 => "Latency Tolerance Reporting",
+            0x0019 => "Secondary PCI Express",
+            0x001e => "L1 PM Substates",
+            _ => return None,
+        })
+    }
+}
+
+impl core::fmt::Display for ExtendedCapability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "[{:03x} v{}] ", self.offset, self.version)?;
+        match self.name() {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "Extended Capability ID {:04x}", self.id),
+        }
+    }
+}
+
+impl Device {
+    pub fn capabilities(&self) -> Capabilities<'_> {
+        let has_list = self
+            .read_conf_reg(REG_COMMAND)
+            .get_bit(STATUS_CAPABILITIES_LIST);
+        Capabilities {
+            device: self,
+            next: if has_list {
+                (self.read_conf_reg(REG_CAPABILITIES_POINTER) & 0xfc) as u16
+            } else {
+                0
+            },
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    pub fn extended_capabilities(&self) -> ExtendedCapabilities<'_> {
+        ExtendedCapabilities {
+            device: self,
+            next: if self.config_space_size() > LEGACY_CONFIG_SPACE_SIZE {
+                LEGACY_CONFIG_SPACE_SIZE
+            } else {
+                0
+            },
+            remaining: MAX_EXTENDED_CAPABILITIES,
+        }
+    }
+
+    fn power_management(&self) -> Result<(u16, PowerManagementInfo), ()> {
+        self.capabilities()
+            .find_map(|cap| match cap.capability {
+                Capability::PowerManagement(pm) => Some((cap.offset, pm)),
+                _ => None,
+            })
+            .ok_or(())
+    }
+
+    pub fn power_state(&self) -> Result<PowerState, ()> {
+        Ok(self.power_management()?.1.power_state)
+    }
+
+    /// Moves the device to `state` through the power management capability and waits
+    /// for the transition. The configuration lost by a reset on D3hot to D0 is restored.
+    pub fn set_power_state(&self, state: PowerState) -> Result<(), ()> {
+        let (offset, pm) = self.power_management()?;
+        let current = pm.power_state;
+        if current == state {
+            return Ok(());
+        }
+        if (state == PowerState::D1 && !pm.d1_supported)
+            || (state == PowerState::D2 && !pm.d2_supported)
+        {
+            return Err(());
+        }
+        // Devices only go to deeper states or directly back to D0
+        if state != PowerState::D0 && state < current {
+            return Err(());
+        }
+
+        let needs_restore =
+            current == PowerState::D3Hot && state == PowerState::D0 && !pm.no_soft_reset;
+        // 0x0c-0x3f holds the BARs, bridge bus numbers and windows and the interrupt line
+        let saved = if needs_restore {
+            let mut saved = [0u32; 13];
+            for (i, value) in saved.iter_mut().enumerate() {
+                *value = self.read_conf_reg(0x0c + 4 * i as u16);
+            }
+            Some((self.read_conf_reg(REG_COMMAND) & 0xffff, saved))
+        } else {
+            None
+        };
+
+        let mut control = self.read_conf_reg(offset + 4);
+        // PME_Status is cleared by writing 1, so write 0 to leave it alone
+        control.set_bit(15, false);
+        control.set_bits(0..2, state as u32);
+        self.write_conf_reg(offset + 4, control);
+
+        // D3hot needs 10ms and D2 needs 200us to settle
+        if state == PowerState::D3Hot || current == PowerState::D3Hot {
+            timer::wait_reference_milliseconds(10);
+        } else if state == PowerState::D2 || current == PowerState::D2 {
+            timer::wait_reference_milliseconds(1);
+        }
+
+        if let Some((command, saved)) = saved {
+            for (i, value) in saved.iter().enumerate() {
+                self.write_conf_reg(0x0c + 4 * i as u16, *value);
+            }
+            self.write_conf_reg(REG_COMMAND, command);
+        }
+
+        if self.power_state()? != state {
+            log!(LogLevel::Warn, "{}: failed to enter {:?}\n", self, state);
+            return Err(());
+        }
+        Ok(())
+    }
+}
+
+fn read_msi_capability(device: &Device, cap_addr: u16) -> MsiCapability {
     let header = read_capability_header(device, cap_addr);
     let msg_addr = device.read_conf_reg(cap_addr + 4);
     let msg_upper_addr;
@@ -399,7 +1283,7 @@ fn read_msi_capability(device: &Device, cap_addr: u8) -> MsiCapability {
     }
 }
 
-fn write_msi_capability(device: &Device, cap_addr: u8, msi_cap: MsiCapability) {
+fn write_msi_capability(device: &Device, cap_addr: u16, msi_cap: MsiCapability) {
     device.write_conf_reg(cap_addr, msi_cap.header.as_u32());
     device.write_conf_reg(cap_addr + 4, msi_cap.msg_addr);
 
@@ -420,7 +1304,7 @@ fn write_msi_capability(device: &Device, cap_addr: u8, msi_cap: MsiCapability) {
 
 fn configure_msi_register(
     device: &Device,
-    cap_addr: u8,
+    cap_addr: u16,
     msg_addr: u32,
     msg_data: u32,
     num_vector_exponent: u8,
@@ -438,15 +1322,149 @@ fn configure_msi_register(
     Ok(())
 }
 
-fn configure_msix_register(
-    _device: &Device,
-    _cap_addr: u8,
-    _msg_addr: u32,
-    _msg_data: u32,
-    _num_vector_exponent: u8,
+// MSI-X message control
+const MSIX_BITS_TABLE_SIZE: Range<usize> = 0..11;
+const MSIX_BIT_FUNCTION_MASK: usize = 14;
+const MSIX_BIT_ENABLE: usize = 15;
+// MSI-X table entry
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_BIT_VECTOR_MASKED: usize = 0;
+
+/// The MSI-X capability of a device with its table and pending bit array mapped.
+pub struct MsixTable {
+    device: Device,
+    cap_addr: u16,
+    table: u64,
+    pending_bits: u64,
+    size: u16,
+}
+
+impl MsixTable {
+    pub fn new(device: &Device) -> Result<Self, ()> {
+        let cap_addr = find_capability(device, CAPABILITY_MSIX).ok_or(())?;
+        Self::from_capability(device, cap_addr)
+    }
+
+    fn from_capability(device: &Device, cap_addr: u16) -> Result<Self, ()> {
+        let header = read_capability_header(device, cap_addr);
+        let table = device.read_conf_reg(cap_addr + 4);
+        let pending_bits = device.read_conf_reg(cap_addr + 8);
+        // The low 3 bits select the BAR and the rest is the offset in it
+        let locate = |value: u32| -> Result<u64, ()> {
+            let bar = device.bar(value.get_bits(0..3) as u8)?;
+            Ok(bar.map()?.as_u64() + u64::from(value & !0x7))
+        };
+
+        Ok(Self {
+            device: device.clone(),
+            cap_addr,
+            table: locate(table)?,
+            pending_bits: locate(pending_bits)?,
+            size: header.cap.get_bits(MSIX_BITS_TABLE_SIZE) + 1,
+        })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn entry_register(&self, index: u16, offset: u64) -> Volatile<&'static mut u32> {
+        let addr = self.table + u64::from(index) * MSIX_ENTRY_SIZE + offset;
+        Volatile::new(unsafe { (addr as *mut u32).as_mut().unwrap() })
+    }
+
+    fn update_message_control(&self, f: impl FnOnce(&mut u16)) {
+        let mut header = read_capability_header(&self.device, self.cap_addr);
+        f(&mut header.cap);
+        self.device.write_conf_reg(self.cap_addr, header.as_u32());
+    }
+
+    /// Programs the message of an entry. The entry stays masked until `unmask` is called.
+    pub fn set_entry(&self, index: u16, msg_addr: u64, msg_data: u32) -> Result<(), ()> {
+        if index >= self.size {
+            return Err(());
+        }
+        self.mask(index)?;
+        self.entry_register(index, 0).write(msg_addr as u32);
+        self.entry_register(index, 4).write((msg_addr >> 32) as u32);
+        self.entry_register(index, 8).write(msg_data);
+        Ok(())
+    }
+
+    fn set_masked(&self, index: u16, masked: bool) -> Result<(), ()> {
+        if index >= self.size {
+            return Err(());
+        }
+        let mut control = self.entry_register(index, 12);
+        let mut value = control.read();
+        value.set_bit(MSIX_BIT_VECTOR_MASKED, masked);
+        control.write(value);
+        Ok(())
+    }
+
+    pub fn mask(&self, index: u16) -> Result<(), ()> {
+        self.set_masked(index, true)
+    }
+
+    pub fn unmask(&self, index: u16) -> Result<(), ()> {
+        self.set_masked(index, false)
+    }
+
+    pub fn is_pending(&self, index: u16) -> Result<bool, ()> {
+        if index >= self.size {
+            return Err(());
+        }
+        let addr = self.pending_bits + u64::from(index / 64) * 8;
+        let bits = Volatile::new(unsafe { (addr as *mut u64).as_mut().unwrap() }).read();
+        Ok(bits.get_bit(usize::from(index % 64)))
+    }
+
+    /// Masks or unmasks all entries at once, keeping the per-entry masks.
+    pub fn set_function_masked(&self, masked: bool) {
+        self.update_message_control(|control| {
+            control.set_bit(MSIX_BIT_FUNCTION_MASK, masked);
+        });
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        if enabled {
+            // MSI and MSI-X must not be enabled at the same time
+            if let Some(msi_cap_addr) = find_capability(&self.device, CAPABILITY_MSI) {
+                let mut header = read_capability_header(&self.device, msi_cap_addr);
+                header.set_msi_enable(false);
+                self.device.write_conf_reg(msi_cap_addr, header.as_u32());
+            }
+        }
+        self.update_message_control(|control| {
+            control.set_bit(MSIX_BIT_ENABLE, enabled);
+        });
+    }
+}
+
+// Programs entry i with the message of `msg_data_list[i]` and enables MSI-X.
+fn configure_msix_entries(
+    table: &MsixTable,
+    msg_addr: u32,
+    msg_data_list: impl ExactSizeIterator<Item = u32>,
 ) -> Result<(), ()> {
-    // Not Implemented
-    Err(())
+    if msg_data_list.len() > usize::from(table.size()) {
+        return Err(());
+    }
+    table.set_function_masked(true);
+    table.set_enabled(true);
+    for (index, msg_data) in (0..).zip(msg_data_list) {
+        table.set_entry(index, u64::from(msg_addr), msg_data)?;
+        table.unmask(index)?;
+    }
+    table.set_function_masked(false);
+    Ok(())
+}
+
+fn find_capability(device: &Device, cap_id: u8) -> Option<u16> {
+    device
+        .capabilities()
+        .find(|cap| cap.id == cap_id)
+        .map(|cap| cap.offset)
 }
 
 fn configure_msi(
@@ -455,40 +1473,68 @@ fn configure_msi(
     msg_data: u32,
     num_vector_exponent: u8,
 ) -> Result<(), ()> {
-    let mut cap_addr = (device.read_conf_reg(0x34) & 0xff) as u8;
-    let mut msi_cap_addr = None;
-    let mut msix_cap_addr = None;
-    while cap_addr != 0 {
-        let header = read_capability_header(device, cap_addr);
-        match header.cap_id {
-            CAPABILITY_MSI => msi_cap_addr = Some(cap_addr),
-            CAPABILITY_MSIX => msix_cap_addr = Some(cap_addr),
-            _ => {}
-        }
-        cap_addr = header.next_ptr;
-    }
-    if let Some(cap_addr) = msi_cap_addr {
-        return configure_msi_register(device, cap_addr, msg_addr, msg_data, num_vector_exponent);
-    }
-    if let Some(cap_addr) = msix_cap_addr {
-        return configure_msix_register(device, cap_addr, msg_addr, msg_data, num_vector_exponent);
+    let cap_addr = find_capability(device, CAPABILITY_MSI).ok_or(())?;
+    configure_msi_register(device, cap_addr, msg_addr, msg_data, num_vector_exponent)
+}
+
+fn fixed_destination_message(
+    apic_id: u32,
+    trigger_mode: MsiTriggerMode,
+    delivery_mode: MsiDeliveryMode,
+    vector: u8,
+) -> (u32, u32) {
+    let msg_addr = 0xfee00000 | (apic_id << 12);
+    let mut msg_data = (delivery_mode.as_u32() << 8) | u32::from(vector);
+    if trigger_mode == MsiTriggerMode::Level {
+        msg_data |= 0xc000;
     }
-    Err(())
+    (msg_addr, msg_data)
 }
 
+/// Configures MSI to raise `vector` and the following vectors up to 2^num_vector_exponent
+/// in total, which the caller reserves together. A device without MSI gets MSI-X
+/// instead, but only for a single vector.
 pub fn configure_msi_fixed_destination(
     device: &Device,
     apic_id: u32,
     trigger_mode: MsiTriggerMode,
     delivery_mode: MsiDeliveryMode,
-    vector: u32,
+    vector: u8,
     num_vector_exponent: u8,
 ) -> Result<(), ()> {
-    let msg_addr = 0xfee00000 | (apic_id << 12);
-    let mut msg_data = (delivery_mode.as_u32() << 8) | vector;
-    if trigger_mode == MsiTriggerMode::Level {
-        msg_data |= 0xc000;
+    if find_capability(device, CAPABILITY_MSI).is_none() {
+        if num_vector_exponent != 0 {
+            return Err(());
+        }
+        return configure_msix_fixed_destination(
+            device,
+            apic_id,
+            trigger_mode,
+            delivery_mode,
+            &[vector],
+        );
     }
+    let (msg_addr, msg_data) =
+        fixed_destination_message(apic_id, trigger_mode, delivery_mode, vector);
     configure_msi(device, msg_addr, msg_data, num_vector_exponent)?;
     Ok(())
 }
+
+/// Configures MSI-X so that table entry i raises `vectors[i]`.
+pub fn configure_msix_fixed_destination(
+    device: &Device,
+    apic_id: u32,
+    trigger_mode: MsiTriggerMode,
+    delivery_mode: MsiDeliveryMode,
+    vectors: &[u8],
+) -> Result<(), ()> {
+    let table = MsixTable::new(device)?;
+    let (msg_addr, _) = fixed_destination_message(apic_id, trigger_mode, delivery_mode, 0);
+    configure_msix_entries(
+        &table,
+        msg_addr,
+        vectors.iter().map(|vector| {
+            fixed_destination_message(apic_id, trigger_mode, delivery_mode, *vector).1
+        }),
+    )
+}
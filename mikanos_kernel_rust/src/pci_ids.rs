@@ -0,0 +1,139 @@
+// Names from data/pci.ids, turned into the tables below by build.rs
+
+pub struct Vendor {
+    pub id: u16,
+    pub name: &'static str,
+    pub devices: &'static [(u16, &'static str)],
+}
+
+pub struct Class {
+    pub id: u8,
+    pub name: &'static str,
+    pub subclasses: &'static [SubClass],
+}
+
+pub struct SubClass {
+    pub id: u8,
+    pub name: &'static str,
+    pub prog_ifs: &'static [(u8, &'static str)],
+}
+
+include!(concat!(env!("OUT_DIR"), "/pci_ids.rs"));
+
+fn find_vendor(vendor_id: u16) -> Option<&'static Vendor> {
+    let index = VENDORS
+        .binary_search_by_key(&vendor_id, |vendor| vendor.id)
+        .ok()?;
+    Some(&VENDORS[index])
+}
+
+fn find_subclass(base_class: u8, sub_class: u8) -> Option<&'static SubClass> {
+    let class = &CLASSES[CLASSES
+        .binary_search_by_key(&base_class, |class| class.id)
+        .ok()?];
+    let index = class
+        .subclasses
+        .binary_search_by_key(&sub_class, |subclass| subclass.id)
+        .ok()?;
+    Some(&class.subclasses[index])
+}
+
+fn find_name<T: Ord + Copy>(table: &'static [(T, &'static str)], id: T) -> Option<&'static str> {
+    let index = table.binary_search_by_key(&id, |entry| entry.0).ok()?;
+    Some(table[index].1)
+}
+
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    Some(find_vendor(vendor_id)?.name)
+}
+
+pub fn device_name(vendor_id: u16, device_id: u16) -> Option<&'static str> {
+    find_name(find_vendor(vendor_id)?.devices, device_id)
+}
+
+pub fn class_name(base_class: u8) -> Option<&'static str> {
+    let index = CLASSES
+        .binary_search_by_key(&base_class, |class| class.id)
+        .ok()?;
+    Some(CLASSES[index].name)
+}
+
+pub fn subclass_name(base_class: u8, sub_class: u8) -> Option<&'static str> {
+    Some(find_subclass(base_class, sub_class)?.name)
+}
+
+pub fn prog_if_name(base_class: u8, sub_class: u8, prog_if: u8) -> Option<&'static str> {
+    find_name(find_subclass(base_class, sub_class)?.prog_ifs, prog_if)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_sorted<T: Ord>(mut ids: impl Iterator<Item = T>) -> bool {
+        let mut prev = match ids.next() {
+            Some(id) => id,
+            None => return true,
+        };
+        ids.all(|id| {
+            let sorted = prev < id;
+            prev = id;
+            sorted
+        })
+    }
+
+    // The lookups binary search the tables build.rs generated
+    #[test_case]
+    fn tables_are_sorted() {
+        assert!(is_sorted(VENDORS.iter().map(|vendor| vendor.id)));
+        for vendor in VENDORS {
+            assert!(is_sorted(vendor.devices.iter().map(|device| device.0)));
+        }
+        assert!(is_sorted(CLASSES.iter().map(|class| class.id)));
+        for class in CLASSES {
+            assert!(is_sorted(
+                class.subclasses.iter().map(|subclass| subclass.id)
+            ));
+            for subclass in class.subclasses {
+                assert!(is_sorted(subclass.prog_ifs.iter().map(|prog_if| prog_if.0)));
+            }
+        }
+    }
+
+    #[test_case]
+    fn finds_every_entry() {
+        for vendor in VENDORS {
+            assert_eq!(vendor_name(vendor.id), Some(vendor.name));
+            for (device_id, name) in vendor.devices {
+                assert_eq!(device_name(vendor.id, *device_id), Some(*name));
+            }
+        }
+        for class in CLASSES {
+            assert_eq!(class_name(class.id), Some(class.name));
+            for subclass in class.subclasses {
+                assert_eq!(subclass_name(class.id, subclass.id), Some(subclass.name));
+            }
+        }
+    }
+
+    #[test_case]
+    fn names_known_ids() {
+        assert_eq!(vendor_name(0x1b36), Some("Red Hat, Inc."));
+        assert_eq!(
+            device_name(0x1b36, 0x000d),
+            Some("QEMU XHCI Host Controller")
+        );
+        assert_eq!(subclass_name(0x0c, 0x03), Some("USB controller"));
+        assert_eq!(prog_if_name(0x0c, 0x03, 0x30), Some("XHCI"));
+    }
+
+    #[test_case]
+    fn unknown_ids_have_no_name() {
+        assert_eq!(vendor_name(0xffff), None);
+        assert_eq!(device_name(0x1b36, 0xffff), None);
+        assert_eq!(device_name(0xffff, 0x000d), None);
+        assert_eq!(class_name(0xfe), None);
+        assert_eq!(subclass_name(0x0c, 0xfe), None);
+        assert_eq!(prog_if_name(0x0c, 0x03, 0xfe), None);
+    }
+}
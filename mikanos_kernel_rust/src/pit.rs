@@ -0,0 +1,45 @@
+use x86_64::instructions::port::Port;
+
+// Input clock of the 8254 Programmable Interval Timer
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL2_DATA: u16 = 0x42;
+const MODE_COMMAND: u16 = 0x43;
+// Bit 0: channel 2 gate, bit 1: speaker enable, bit 5: channel 2 output
+const CHANNEL2_CONTROL: u16 = 0x61;
+
+// The longest wait a 16 bit count can express
+const MAX_WAIT_MILLISECONDS: u64 = 0xffff * 1000 / PIT_FREQUENCY;
+
+fn wait_once(milliseconds: u64) {
+    let mut control = Port::<u8>::new(CHANNEL2_CONTROL);
+    let mut mode = Port::<u8>::new(MODE_COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL2_DATA);
+    let count = (PIT_FREQUENCY * milliseconds / 1000) as u16;
+
+    unsafe {
+        // Disable the gate and the speaker while programming
+        let value = control.read() & !0x03;
+        control.write(value);
+        // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+        mode.write(0b1011_0000);
+        data.write((count & 0xff) as u8);
+        data.write((count >> 8) as u8);
+        // Raising the gate starts counting
+        control.write(value | 0x01);
+        while control.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        control.write(value);
+    }
+}
+
+/// Busy-waits using PIT channel 2. Only intended as a reference for calibrating other timers.
+pub fn wait_milliseconds(milliseconds: u64) {
+    let mut remaining = milliseconds;
+    while remaining > 0 {
+        let wait = remaining.min(MAX_WAIT_MILLISECONDS);
+        wait_once(wait);
+        remaining -= wait;
+    }
+}
@@ -0,0 +1,137 @@
+use crate::acpi::{self, Fadt};
+use crate::logger::Level as LogLevel;
+use crate::{hlt_loop, log, timer};
+use bit_field::BitField;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::tables::lidt;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+// PM1 control register
+const PM1_CNT_SCI_EN: usize = 0;
+const PM1_CNT_SLP_TYP: core::ops::Range<usize> = 10..13;
+const PM1_CNT_SLP_EN: usize = 13;
+
+// 8042 keyboard controller
+const KBC_STATUS_COMMAND: u16 = 0x64;
+const KBC_STATUS_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+// How long to give each method before trying the next one
+const RESET_WAIT_MILLISECONDS: u64 = 500;
+const ACPI_ENABLE_TIMEOUT_MILLISECONDS: u64 = 1000;
+
+fn reset_by_acpi() -> Result<(), ()> {
+    let fadt = &acpi::tables().ok_or(())?.fadt;
+    if !fadt.reset_register_supported() {
+        return Err(());
+    }
+    fadt.reset_register.ok_or(())?.write_u8(fadt.reset_value)
+}
+
+fn reset_by_keyboard_controller() {
+    let mut port = Port::<u8>::new(KBC_STATUS_COMMAND);
+    unsafe {
+        for _ in 0..0x10000 {
+            if port.read() & KBC_STATUS_INPUT_FULL == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        port.write(KBC_PULSE_RESET);
+    }
+}
+
+fn triple_fault() {
+    // Any interrupt with an empty IDT escalates to a triple fault
+    let idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe { lidt(&idt) };
+    interrupts::int3();
+}
+
+/// Restarts the machine. Tries the ACPI reset register, the keyboard controller and
+/// a triple fault in this order.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    log!(LogLevel::Info, "Rebooting\n");
+
+    if reset_by_acpi().is_ok() {
+        timer::wait_reference_milliseconds(RESET_WAIT_MILLISECONDS);
+    }
+    reset_by_keyboard_controller();
+    timer::wait_reference_milliseconds(RESET_WAIT_MILLISECONDS);
+    triple_fault();
+
+    hlt_loop();
+}
+
+fn pm1_control(port: u32) -> Port<u16> {
+    Port::new(port as u16)
+}
+
+// The firmware may still own the power management registers until ACPI mode is entered
+fn enable_acpi_mode(fadt: &Fadt) -> Result<(), ()> {
+    let mut pm1a_control = pm1_control(fadt.pm1a_control_block);
+    if unsafe { pm1a_control.read() }.get_bit(PM1_CNT_SCI_EN) {
+        return Ok(());
+    }
+    if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return Err(());
+    }
+
+    unsafe { Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable) };
+    for _ in 0..ACPI_ENABLE_TIMEOUT_MILLISECONDS {
+        if unsafe { pm1a_control.read() }.get_bit(PM1_CNT_SCI_EN) {
+            return Ok(());
+        }
+        timer::wait_reference_milliseconds(1);
+    }
+    Err(())
+}
+
+fn enter_sleep_state(port: u32, sleep_type: u16) {
+    let mut pm1_control = pm1_control(port);
+    let mut value = unsafe { pm1_control.read() };
+    value.set_bits(PM1_CNT_SLP_TYP, sleep_type);
+    value.set_bit(PM1_CNT_SLP_EN, true);
+    unsafe { pm1_control.write(value) };
+}
+
+fn enter_s5() -> Result<(), ()> {
+    let tables = acpi::tables().ok_or(())?;
+    let fadt = &tables.fadt;
+    let s5 = tables.s5_sleep_type.ok_or(())?;
+    if fadt.pm1a_control_block == 0 {
+        return Err(());
+    }
+
+    enable_acpi_mode(fadt)?;
+    enter_sleep_state(fadt.pm1a_control_block, s5.pm1a);
+    if fadt.pm1b_control_block != 0 {
+        enter_sleep_state(fadt.pm1b_control_block, s5.pm1b);
+    }
+    timer::wait_reference_milliseconds(RESET_WAIT_MILLISECONDS);
+    Ok(())
+}
+
+/// Powers off the machine by entering the ACPI S5 sleep state.
+/// Halts forever if the firmware does not support it.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    log!(LogLevel::Info, "Shutting down\n");
+
+    match enter_s5() {
+        Ok(()) => {
+            log!(LogLevel::Error, "Failed to enter S5\n");
+        }
+        Err(()) => {
+            log!(LogLevel::Error, "ACPI S5 is not available\n");
+        }
+    }
+
+    hlt_loop();
+}
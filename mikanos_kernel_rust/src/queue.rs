@@ -1,10 +1,13 @@
 use crate::sync::once_cell::OnceCell;
+use crate::timer::Timeout;
 use alloc::sync::Arc;
 use crossbeam_queue::ArrayQueue;
 
 #[derive(Debug)]
 pub enum QueueEventType {
     InterruptXHCI,
+    TimerTick,
+    TimerTimeout(Timeout),
 }
 
 #[derive(Debug)]
@@ -0,0 +1,254 @@
+use crate::acpi;
+use core::fmt;
+use core::fmt::Formatter;
+use x86_64::instructions::port::Port;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY_OF_MONTH: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+// Status register A
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+// Status register B
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+// Set in the hours register for PM in 12 hour mode
+const HOUR_PM: u8 = 1 << 7;
+
+// Used when no century register is known
+const DEFAULT_CENTURY: u16 = 20;
+
+#[derive(Debug)]
+struct PortSet {
+    addr: Port<u8>,
+    data: Port<u8>,
+}
+
+static CMOS: spin::Mutex<PortSet> = spin::Mutex::new(PortSet {
+    addr: Port::new(0x70),
+    data: Port::new(0x71),
+});
+
+fn read_register(ports: &mut PortSet, reg: u8) -> u8 {
+    unsafe {
+        ports.addr.write(reg);
+        ports.data.read()
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+fn read_raw_time(ports: &mut PortSet) -> RawTime {
+    while read_register(ports, REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    RawTime {
+        second: read_register(ports, REG_SECONDS),
+        minute: read_register(ports, REG_MINUTES),
+        hour: read_register(ports, REG_HOURS),
+        day: read_register(ports, REG_DAY_OF_MONTH),
+        month: read_register(ports, REG_MONTH),
+        year: read_register(ports, REG_YEAR),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC, assuming the RTC keeps UTC.
+    pub fn unix_timestamp(&self) -> u64 {
+        // Days from civil algorithm by Howard Hinnant
+        let year = i64::from(self.year) - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = i64::from(self.month);
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        (days * 86400
+            + i64::from(self.hour) * 3600
+            + i64::from(self.minute) * 60
+            + i64::from(self.second)) as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Reads the current date and time from the CMOS real-time clock.
+pub fn now() -> DateTime {
+    read_time(&mut CMOS.lock())
+}
+
+fn read_time(ports: &mut PortSet) -> DateTime {
+    // The RTC may update between reads, so read until two results agree.
+    let mut time = read_raw_time(ports);
+    loop {
+        let next = read_raw_time(ports);
+        if next == time {
+            break;
+        }
+        time = next;
+    }
+    let status_b = read_register(ports, REG_STATUS_B);
+    // The FADT tells where the firmware keeps the century, if anywhere
+    let century = acpi::tables()
+        .map(|tables| tables.fadt.century)
+        .filter(|reg| *reg != 0)
+        .map(|reg| read_register(ports, reg));
+    decode_time(time, status_b, century)
+}
+
+// Converts the registers from BCD and 12 hour mode as status register B says
+fn decode_time(mut time: RawTime, status_b: u8, mut century: Option<u8>) -> DateTime {
+    let pm = time.hour & HOUR_PM != 0;
+    time.hour &= !HOUR_PM;
+    if status_b & STATUS_B_BINARY == 0 {
+        time.second = bcd_to_binary(time.second);
+        time.minute = bcd_to_binary(time.minute);
+        time.hour = bcd_to_binary(time.hour);
+        time.day = bcd_to_binary(time.day);
+        time.month = bcd_to_binary(time.month);
+        time.year = bcd_to_binary(time.year);
+        century = century.map(bcd_to_binary);
+    }
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is 0 o'clock and 12 PM is 12 o'clock
+        time.hour %= 12;
+        if pm {
+            time.hour += 12;
+        }
+    }
+
+    DateTime {
+        year: century.map_or(DEFAULT_CENTURY, u16::from) * 100 + u16::from(time.year),
+        month: time.month,
+        day: time.day,
+        hour: time.hour,
+        minute: time.minute,
+        second: time.second,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BCD_24_HOUR: u8 = STATUS_B_24_HOUR;
+    const BCD_12_HOUR: u8 = 0;
+    const BINARY_24_HOUR: u8 = STATUS_B_BINARY | STATUS_B_24_HOUR;
+
+    fn raw(hour: u8) -> RawTime {
+        RawTime {
+            second: 0x59,
+            minute: 0x30,
+            hour,
+            day: 0x31,
+            month: 0x12,
+            year: 0x99,
+        }
+    }
+
+    #[test_case]
+    fn decodes_bcd() {
+        assert_eq!(
+            decode_time(raw(0x23), BCD_24_HOUR, Some(0x19)),
+            DateTime {
+                year: 1999,
+                month: 12,
+                day: 31,
+                hour: 23,
+                minute: 30,
+                second: 59,
+            }
+        );
+    }
+
+    #[test_case]
+    fn decodes_binary() {
+        let time = RawTime {
+            second: 59,
+            minute: 30,
+            hour: 23,
+            day: 31,
+            month: 12,
+            year: 99,
+        };
+        assert_eq!(
+            decode_time(time, BINARY_24_HOUR, Some(19)),
+            decode_time(raw(0x23), BCD_24_HOUR, Some(0x19))
+        );
+    }
+
+    #[test_case]
+    fn converts_12_hour_mode() {
+        let hour = |raw_hour| decode_time(raw(raw_hour), BCD_12_HOUR, None).hour;
+        // 12 AM is midnight and 12 PM is noon
+        assert_eq!(hour(0x12), 0);
+        assert_eq!(hour(0x01), 1);
+        assert_eq!(hour(HOUR_PM | 0x12), 12);
+        assert_eq!(hour(HOUR_PM | 0x01), 13);
+        assert_eq!(hour(HOUR_PM | 0x11), 23);
+    }
+
+    #[test_case]
+    fn uses_default_century_without_register() {
+        assert_eq!(decode_time(raw(0x00), BCD_24_HOUR, None).year, 2099);
+    }
+
+    #[test_case]
+    fn computes_unix_timestamp() {
+        let epoch = DateTime {
+            year: 1970,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        };
+        assert_eq!(epoch.unix_timestamp(), 0);
+        let leap_day = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 12,
+            minute: 34,
+            second: 56,
+        };
+        assert_eq!(leap_day.unix_timestamp(), 1_709_210_096);
+    }
+}
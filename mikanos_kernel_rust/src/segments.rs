@@ -2,21 +2,57 @@
 
 use core::mem;
 use modular_bitfield::prelude::*;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+pub const KERNEL_CS: u16 = 1 << 3;
+pub const KERNEL_SS: u16 = 2 << 3;
+pub const TSS_SELECTOR: u16 = 3 << 3;
+
+// Indexes into the Interrupt Stack Table, as passed to EntryOptions::set_stack_index
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+const IST_STACK_COUNT: usize = 3;
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+#[repr(align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+static mut IST_STACKS: [IstStack; IST_STACK_COUNT] = [
+    IstStack([0; IST_STACK_SIZE]),
+    IstStack([0; IST_STACK_SIZE]),
+    IstStack([0; IST_STACK_SIZE]),
+];
+
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 pub fn init() {
     unsafe {
+        for (i, stack) in IST_STACKS.iter().enumerate() {
+            // Stacks grow downwards
+            let stack_end = VirtAddr::from_ptr(stack.0.as_ptr_range().end);
+            TSS.interrupt_stack_table[i] = stack_end;
+        }
+
         GDT[1].initialize_code_segment(0);
         GDT[2].initialize_data_segment(0);
+        let tss_base = &TSS as *const TaskStateSegment as u64;
+        GDT[3].initialize_tss_segment(tss_base, (mem::size_of::<TaskStateSegment>() - 1) as u32);
+        GDT[4] = SegmentDescriptor::tss_segment_upper(tss_base);
         load_gdt(
             (GDT.len() * mem::size_of::<SegmentDescriptor>() - 1) as u16,
             mem::transmute(&GDT[0]),
         );
         set_ds_all(0);
-        set_csss(1 << 3, 2 << 3);
+        set_csss(KERNEL_CS, KERNEL_SS);
+        load_tr(TSS_SELECTOR);
     }
 }
 
-static mut GDT: [SegmentDescriptor; 3] = [SegmentDescriptor::new(); 3];
+// A TSS descriptor occupies two entries (GDT[3] and GDT[4]) in long mode.
+static mut GDT: [SegmentDescriptor; 5] = [SegmentDescriptor::new(); 5];
 
 #[derive(BitfieldSpecifier, Debug)]
 #[bits = 4]
@@ -70,10 +106,32 @@ impl SegmentDescriptor {
         self.set_long_mode(false);
         self.set_default_operation_size(true);
     }
+
+    fn initialize_tss_segment(&mut self, base: u64, limit: u32) {
+        self.set_limit_low((limit & 0xffff) as u16);
+        self.set_limit_high(((limit >> 16) & 0xf) as u8);
+        self.set_base_low((base & 0xffff) as u16);
+        self.set_base_middle(((base >> 16) & 0xff) as u8);
+        self.set_base_high(((base >> 24) & 0xff) as u8);
+        self.set_descriptor_type(DescriptorType::TSSAvailable);
+        self.set_system_segment(false);
+        self.set_dpl(0);
+        self.set_present(true);
+        self.set_available(false);
+        self.set_long_mode(false);
+        self.set_default_operation_size(false);
+        self.set_granularity(false);
+    }
+
+    // The upper 8 bytes of a TSS descriptor hold bits 32-63 of the base address.
+    fn tss_segment_upper(base: u64) -> Self {
+        Self::from_bytes((base >> 32).to_le_bytes())
+    }
 }
 
 extern "C" {
     fn load_gdt(limit: u16, offset: *const u64);
     fn set_csss(cs: u16, ss: u16);
     fn set_ds_all(value: u16);
+    fn load_tr(selector: u16);
 }
@@ -0,0 +1,636 @@
+use crate::memory_manager::{memory_manager, try_memory_manager, FrameId};
+use crate::paging::{self, as_phys_addr, as_virt_addr};
+use core::cmp::Ordering;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicPtr, Ordering as AtomicOrdering};
+use spin::mutex::SpinMutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+// A slab holds at least this many objects, so large objects get slabs of several frames
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+// Empty slabs kept for reuse. Any more are given back to the frame allocator.
+const MAX_EMPTY_SLABS: usize = 1;
+// Objects from this size keep the slab header out of the slab. Objects are aligned to
+// their size, so a header on the slab would take the place of a whole object.
+const OFF_SLAB_OBJECT_SIZE: usize = FrameId::SIZE / 8;
+
+// Slabs are power-of-two frames, which memory_manager aligns to their size, so the
+// slab of an object is found by masking its address. The header is at the start of
+// the slab, or comes from SLAB_HEADERS for large objects and is found in SLAB_OWNERS.
+struct SlabHeader {
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+    free_objects: *mut FreeObject,
+    in_use: usize,
+    start: *mut u8,
+}
+
+// Small objects, so its own headers are on the slab
+static SLAB_HEADERS: SpinMutex<SlabCache> = SpinMutex::new(SlabCache::new(
+    "slab",
+    mem::size_of::<SlabHeader>(),
+    mem::align_of::<SlabHeader>(),
+));
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+// Off-slab headers by the first frame of their slab. Each leaf is a frame covering
+// OWNERS_PER_LEAF frames, taken from memory_manager when first needed and kept.
+const OWNERS_PER_LEAF: usize = FrameId::SIZE / mem::size_of::<AtomicPtr<SlabHeader>>();
+const OWNER_LEAF_COUNT: usize =
+    paging::IDENTITY_MAPPED_BYTES as usize / FrameId::SIZE / OWNERS_PER_LEAF;
+
+type OwnerLeaf = [AtomicPtr<SlabHeader>; OWNERS_PER_LEAF];
+
+// Only used to initialize SLAB_OWNERS
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_LEAF: AtomicPtr<OwnerLeaf> = AtomicPtr::new(ptr::null_mut());
+static SLAB_OWNERS: [AtomicPtr<OwnerLeaf>; OWNER_LEAF_COUNT] = [NO_LEAF; OWNER_LEAF_COUNT];
+
+fn owner_index(start: *mut u8) -> (usize, usize) {
+    let addr = as_phys_addr(x86_64::VirtAddr::from_ptr(start)).unwrap();
+    let frame = addr.as_u64() as usize / FrameId::SIZE;
+    (frame / OWNERS_PER_LEAF, frame % OWNERS_PER_LEAF)
+}
+
+fn slab_owner(start: *mut u8) -> *mut SlabHeader {
+    let (leaf, index) = owner_index(start);
+    let leaf = SLAB_OWNERS[leaf].load(AtomicOrdering::Acquire);
+    if leaf.is_null() {
+        return ptr::null_mut();
+    }
+    unsafe { (*leaf)[index].load(AtomicOrdering::Acquire) }
+}
+
+// Takes a frame for a new leaf, so it is not for interrupt handlers
+fn set_slab_owner(start: *mut u8, slab: *mut SlabHeader) -> Result<(), ()> {
+    let (leaf_index, index) = owner_index(start);
+    let mut leaf = SLAB_OWNERS[leaf_index].load(AtomicOrdering::Acquire);
+    if leaf.is_null() {
+        let frame = memory_manager().allocate(1)?;
+        let new_leaf: *mut OwnerLeaf = as_virt_addr(frame.to_physical_address())
+            .unwrap()
+            .as_mut_ptr();
+        // All null
+        unsafe { ptr::write_bytes(new_leaf, 0, 1) };
+        leaf = match SLAB_OWNERS[leaf_index].compare_exchange(
+            ptr::null_mut(),
+            new_leaf,
+            AtomicOrdering::AcqRel,
+            AtomicOrdering::Acquire,
+        ) {
+            Ok(_) => new_leaf,
+            // Another cache added the leaf in between
+            Err(leaf) => {
+                memory_manager().free(frame, 1);
+                leaf
+            }
+        };
+    }
+    unsafe { (*leaf)[index].store(slab, AtomicOrdering::Release) };
+    Ok(())
+}
+
+fn clear_slab_owner(start: *mut u8) {
+    let (leaf, index) = owner_index(start);
+    let leaf = SLAB_OWNERS[leaf].load(AtomicOrdering::Acquire);
+    if !leaf.is_null() {
+        unsafe { (*leaf)[index].store(ptr::null_mut(), AtomicOrdering::Release) };
+    }
+}
+
+struct SlabList {
+    head: *mut SlabHeader,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut SlabHeader) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut SlabHeader) {
+        if (*slab).prev.is_null() {
+            self.head = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        self.len -= 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub slab_frames: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    pub objects_in_use: u64,
+    pub partial_slabs: usize,
+    pub full_slabs: usize,
+    pub empty_slabs: usize,
+    pub slabs_created: u64,
+    pub slabs_released: u64,
+}
+
+impl fmt::Display for SlabStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} bytes, {} in use, {} allocs, {} frees, slabs {}/{}/{} (partial/full/empty) of {} frames, {} created, {} released",
+            self.name,
+            self.object_size,
+            self.objects_in_use,
+            self.allocations,
+            self.frees,
+            self.partial_slabs,
+            self.full_slabs,
+            self.empty_slabs,
+            self.slab_frames,
+            self.slabs_created,
+            self.slabs_released
+        )
+    }
+}
+
+/// Objects of one size, carved out of slabs taken from memory_manager.
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    slab_frames: usize,
+    off_slab_header: bool,
+    first_object_offset: usize,
+    objects_per_slab: usize,
+    partial: SlabList,
+    full: SlabList,
+    empty: SlabList,
+    allocations: u64,
+    frees: u64,
+    slabs_created: u64,
+    slabs_released: u64,
+}
+
+unsafe impl Send for SlabCache {}
+
+const fn round_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
+
+impl SlabCache {
+    pub const fn new(name: &'static str, object_size: usize, align: usize) -> Self {
+        assert!(align.is_power_of_two() && align <= FrameId::SIZE);
+        // Free objects hold the link of the free list
+        let object_size = if object_size < mem::size_of::<FreeObject>() {
+            mem::size_of::<FreeObject>()
+        } else {
+            object_size
+        };
+        let align = if align < mem::align_of::<FreeObject>() {
+            mem::align_of::<FreeObject>()
+        } else {
+            align
+        };
+        let object_size = round_up(object_size, align);
+        let off_slab_header = object_size >= OFF_SLAB_OBJECT_SIZE;
+        let first_object_offset = if off_slab_header {
+            0
+        } else {
+            round_up(mem::size_of::<SlabHeader>(), align)
+        };
+
+        let mut slab_frames = 1;
+        while slab_frames * FrameId::SIZE < first_object_offset + MIN_OBJECTS_PER_SLAB * object_size
+        {
+            slab_frames *= 2;
+        }
+
+        Self {
+            name,
+            object_size,
+            slab_frames,
+            off_slab_header,
+            first_object_offset,
+            objects_per_slab: (slab_frames * FrameId::SIZE - first_object_offset) / object_size,
+            partial: SlabList::new(),
+            full: SlabList::new(),
+            empty: SlabList::new(),
+            allocations: 0,
+            frees: 0,
+            slabs_created: 0,
+            slabs_released: 0,
+        }
+    }
+
+    fn slab_bytes(&self) -> usize {
+        self.slab_frames * FrameId::SIZE
+    }
+
+    fn list_for(&mut self, in_use: usize) -> &mut SlabList {
+        if in_use == 0 {
+            &mut self.empty
+        } else if in_use == self.objects_per_slab {
+            &mut self.full
+        } else {
+            &mut self.partial
+        }
+    }
+
+    // The slab containing an allocated object
+    unsafe fn slab_of(&self, ptr: *mut u8) -> *mut SlabHeader {
+        let start = (ptr as usize & !(self.slab_bytes() - 1)) as *mut u8;
+        if !self.off_slab_header {
+            return start as *mut SlabHeader;
+        }
+        let slab = slab_owner(start);
+        assert!(
+            !slab.is_null(),
+            "slab: freeing an object which is not allocated"
+        );
+        slab
+    }
+
+    fn create_slab(&mut self) -> Result<*mut SlabHeader, ()> {
+        let slab = if self.off_slab_header {
+            let header = SLAB_HEADERS.lock().allocate() as *mut SlabHeader;
+            if header.is_null() {
+                return Err(());
+            }
+            header
+        } else {
+            ptr::null_mut()
+        };
+        let frame = match memory_manager().allocate(self.slab_frames) {
+            Ok(frame) => frame,
+            Err(()) => {
+                if !slab.is_null() {
+                    unsafe { SLAB_HEADERS.lock().free(slab as *mut u8) };
+                }
+                return Err(());
+            }
+        };
+        let start: *mut u8 = as_virt_addr(frame.to_physical_address())
+            .unwrap()
+            .as_mut_ptr();
+        if !slab.is_null() && set_slab_owner(start, slab).is_err() {
+            memory_manager().free(frame, self.slab_frames);
+            unsafe { SLAB_HEADERS.lock().free(slab as *mut u8) };
+            return Err(());
+        }
+
+        let mut free_objects = ptr::null_mut();
+        for i in (0..self.objects_per_slab).rev() {
+            let object = unsafe { start.add(self.first_object_offset + i * self.object_size) }
+                as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: free_objects }) };
+            free_objects = object;
+        }
+
+        let slab = if slab.is_null() {
+            start as *mut SlabHeader
+        } else {
+            slab
+        };
+        unsafe {
+            slab.write(SlabHeader {
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                free_objects,
+                in_use: 0,
+                start,
+            });
+            self.empty.push(slab);
+        }
+        self.slabs_created += 1;
+        Ok(slab)
+    }
+
+    // Objects may be freed by interrupt handlers, so this gives up instead of waiting
+    // for memory_manager or SLAB_HEADERS, which the interrupted code may hold.
+    fn try_release_slab(&mut self, slab: *mut SlabHeader) -> bool {
+        let mut headers = None;
+        if self.off_slab_header {
+            match SLAB_HEADERS.try_lock() {
+                Some(lock) => headers = Some(lock),
+                None => return false,
+            }
+        }
+        let mut manager = match try_memory_manager() {
+            Some(manager) => manager,
+            None => return false,
+        };
+
+        let start = unsafe { (*slab).start };
+        let addr = x86_64::VirtAddr::from_ptr(start);
+        let frame = FrameId::from_physical_address(as_phys_addr(addr).unwrap());
+        manager.free(frame, self.slab_frames);
+        drop(manager);
+        if let Some(mut headers) = headers {
+            clear_slab_owner(start);
+            unsafe { headers.free(slab as *mut u8) };
+        }
+        self.slabs_released += 1;
+        true
+    }
+
+    pub fn allocate(&mut self) -> *mut u8 {
+        let slab = if !self.partial.head.is_null() {
+            self.partial.head
+        } else if !self.empty.head.is_null() {
+            self.empty.head
+        } else {
+            match self.create_slab() {
+                Ok(slab) => slab,
+                Err(()) => return ptr::null_mut(),
+            }
+        };
+
+        unsafe {
+            let in_use = (*slab).in_use;
+            self.list_for(in_use).remove(slab);
+            let object = (*slab).free_objects;
+            (*slab).free_objects = (*object).next;
+            (*slab).in_use += 1;
+            self.list_for(in_use + 1).push(slab);
+            self.allocations += 1;
+            object as *mut u8
+        }
+    }
+
+    /// # Safety
+    /// `ptr` must have been returned by `allocate` of this cache and not freed since.
+    pub unsafe fn free(&mut self, ptr: *mut u8) {
+        let slab = self.slab_of(ptr);
+        let in_use = (*slab).in_use;
+        self.list_for(in_use).remove(slab);
+
+        let object = ptr as *mut FreeObject;
+        (*object).next = (*slab).free_objects;
+        (*slab).free_objects = object;
+        (*slab).in_use -= 1;
+        self.frees += 1;
+
+        if in_use == 1 && self.empty.len >= MAX_EMPTY_SLABS && self.try_release_slab(slab) {
+            return;
+        }
+        self.list_for(in_use - 1).push(slab);
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            name: self.name,
+            object_size: self.object_size,
+            slab_frames: self.slab_frames,
+            allocations: self.allocations,
+            frees: self.frees,
+            objects_in_use: self.allocations - self.frees,
+            partial_slabs: self.partial.len,
+            full_slabs: self.full.len,
+            empty_slabs: self.empty.len,
+            slabs_created: self.slabs_created,
+            slabs_released: self.slabs_released,
+        }
+    }
+}
+
+/// A named cache for a frequently allocated type, usually kept in a static.
+pub struct ObjectCache<T> {
+    cache: SpinMutex<SlabCache>,
+    _marker: PhantomData<T>,
+}
+
+impl<T> ObjectCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            cache: SpinMutex::new(SlabCache::new(
+                name,
+                mem::size_of::<T>(),
+                mem::align_of::<T>(),
+            )),
+            _marker: PhantomData,
+        }
+    }
+
+    // Objects may be dropped in interrupt handlers, e.g. fired timers, so the lock is
+    // never held with interrupts enabled.
+    fn with_cache<R>(&self, f: impl FnOnce(&mut SlabCache) -> R) -> R {
+        without_interrupts(|| f(&mut self.cache.lock()))
+    }
+
+    pub fn alloc(&'static self, value: T) -> Result<CachedBox<T>, ()> {
+        let ptr = NonNull::new(self.with_cache(|cache| cache.allocate()) as *mut T).ok_or(())?;
+        unsafe { ptr.as_ptr().write(value) };
+        Ok(CachedBox { ptr, cache: self })
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.with_cache(|cache| cache.stats())
+    }
+}
+
+/// An object owned by an ObjectCache. It goes back to the cache when dropped.
+pub struct CachedBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static ObjectCache<T>,
+}
+
+unsafe impl<T: Send> Send for CachedBox<T> {}
+unsafe impl<T: Sync> Sync for CachedBox<T> {}
+
+impl<T> Deref for CachedBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for CachedBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+// Compares the objects, like Box, so that CachedBox can go in ordered collections
+impl<T: PartialEq> PartialEq for CachedBox<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Eq> Eq for CachedBox<T> {}
+
+impl<T: PartialOrd> PartialOrd for CachedBox<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: Ord> Ord for CachedBox<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for CachedBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for CachedBox<T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.ptr.as_ptr()) };
+        let ptr = self.ptr.as_ptr() as *mut u8;
+        self.cache.with_cache(|cache| unsafe { cache.free(ptr) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    // Caches in tests are not statics, so their empty slabs are given back here
+    fn release_empty_slabs(cache: &mut SlabCache) {
+        while !cache.empty.head.is_null() {
+            let slab = cache.empty.head;
+            unsafe { cache.empty.remove(slab) };
+            assert!(cache.try_release_slab(slab));
+        }
+    }
+
+    fn allocate(cache: &mut SlabCache, count: usize) -> Vec<*mut u8> {
+        (0..count)
+            .map(|_| {
+                let object = cache.allocate();
+                assert!(!object.is_null());
+                object
+            })
+            .collect()
+    }
+
+    fn free(cache: &mut SlabCache, objects: &[*mut u8]) {
+        for object in objects {
+            unsafe { cache.free(*object) };
+        }
+    }
+
+    #[test_case]
+    fn tracks_slab_occupancy() {
+        let mut cache = SlabCache::new("test", 64, 64);
+        let per_slab = cache.objects_per_slab;
+        let first = allocate(&mut cache, per_slab);
+        let stats = cache.stats();
+        assert_eq!((stats.partial_slabs, stats.full_slabs), (0, 1));
+
+        let second = allocate(&mut cache, 1);
+        let stats = cache.stats();
+        assert_eq!((stats.partial_slabs, stats.full_slabs), (1, 1));
+        assert_eq!(stats.objects_in_use, per_slab as u64 + 1);
+        assert_eq!(stats.slabs_created, 2);
+
+        free(&mut cache, &first[..1]);
+        let stats = cache.stats();
+        assert_eq!((stats.partial_slabs, stats.full_slabs), (2, 0));
+
+        free(&mut cache, &first[1..]);
+        free(&mut cache, &second);
+        release_empty_slabs(&mut cache);
+    }
+
+    #[test_case]
+    fn gives_back_empty_slabs() {
+        let mut cache = SlabCache::new("test", 64, 64);
+        let per_slab = cache.objects_per_slab;
+        let objects = allocate(&mut cache, 2 * per_slab);
+        let free_frames = memory_manager().free_frames();
+
+        free(&mut cache, &objects);
+        let stats = cache.stats();
+        // One empty slab is kept for reuse
+        assert_eq!(stats.empty_slabs, MAX_EMPTY_SLABS);
+        assert_eq!(stats.slabs_released, 1);
+        assert_eq!(stats.objects_in_use, 0);
+        assert_eq!(
+            memory_manager().free_frames(),
+            free_frames + cache.slab_frames
+        );
+
+        release_empty_slabs(&mut cache);
+    }
+
+    #[test_case]
+    fn keeps_empty_slabs_while_memory_manager_is_busy() {
+        let mut cache = SlabCache::new("test", 64, 64);
+        let per_slab = cache.objects_per_slab;
+        let objects = allocate(&mut cache, 2 * per_slab);
+
+        // As if the interrupted code held it
+        let manager = memory_manager();
+        free(&mut cache, &objects);
+        drop(manager);
+        let stats = cache.stats();
+        assert_eq!(stats.empty_slabs, 2);
+        assert_eq!(stats.slabs_released, 0);
+
+        release_empty_slabs(&mut cache);
+    }
+
+    #[test_case]
+    fn large_objects_fill_the_slab() {
+        let mut cache = SlabCache::new("test", 2048, 2048);
+        assert!(cache.off_slab_header);
+        assert_eq!(cache.objects_per_slab * 2048, cache.slab_bytes());
+
+        let per_slab = cache.objects_per_slab;
+        let objects = allocate(&mut cache, per_slab);
+        let start = objects.iter().map(|o| *o as usize).min().unwrap();
+        assert_eq!(start % cache.slab_bytes(), 0);
+        for object in &objects {
+            assert_eq!(*object as usize % 2048, 0);
+            assert!((*object as usize) < start + cache.slab_bytes());
+        }
+        assert_eq!(cache.stats().full_slabs, 1);
+
+        assert_eq!(slab_owner(start as *mut u8), cache.full.head);
+
+        free(&mut cache, &objects);
+        release_empty_slabs(&mut cache);
+        assert!(slab_owner(start as *mut u8).is_null());
+    }
+
+    #[test_case]
+    fn cached_box_returns_to_its_cache() {
+        static CACHE: ObjectCache<[u64; 4]> = ObjectCache::new("test");
+        let in_use = CACHE.stats().objects_in_use;
+        let mut object = CACHE.alloc([1, 2, 3, 4]).unwrap();
+        object[3] = 5;
+        assert_eq!(*object, [1, 2, 3, 5]);
+        assert_eq!(CACHE.stats().objects_in_use, in_use + 1);
+        drop(object);
+        assert_eq!(CACHE.stats().objects_in_use, in_use);
+    }
+}
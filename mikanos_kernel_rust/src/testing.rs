@@ -0,0 +1,40 @@
+// Runs the #[test_case] functions inside the kernel under QEMU. run_test.sh starts
+// QEMU with an isa-debug-exit device, so that a failing test can end the run with a
+// nonzero exit status. Passing runs power off through ACPI.
+
+use crate::{hlt_loop, power, printk};
+use x86_64::instructions::port::Port;
+
+// Must match the isa-debug-exit device in run_test.sh
+const QEMU_EXIT_PORT: u16 = 0xf4;
+// QEMU exits with (value << 1) | 1
+const QEMU_EXIT_FAILURE: u32 = 0x01;
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        printk!("{} ... ", core::any::type_name::<T>());
+        self();
+        printk!("ok\n");
+    }
+}
+
+pub fn run_tests(tests: &[&dyn Testable]) {
+    printk!("Running {} tests\n", tests.len());
+    for test in tests {
+        test.run();
+    }
+    printk!("All tests passed\n");
+    power::shutdown();
+}
+
+/// Ends the QEMU run with a failure. Called by the panic handler in test builds.
+pub fn fail() -> ! {
+    printk!("FAILED\n");
+    unsafe { Port::<u32>::new(QEMU_EXIT_PORT).write(QEMU_EXIT_FAILURE) };
+    // Not running in QEMU
+    hlt_loop();
+}
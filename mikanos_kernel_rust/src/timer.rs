@@ -0,0 +1,357 @@
+use crate::apic::{local_apic, Lvt, LvtEntry, TimerDivide, TimerMode};
+use crate::hpet::TimerMode as HpetTimerMode;
+use crate::logger::Level as LogLevel;
+use crate::queue::{event_queue, QueueEvent, QueueEventType};
+use crate::slab::{CachedBox, ObjectCache, SlabStats};
+use crate::sync::once_cell::OnceCell;
+use crate::{acpi, hpet, interrupt, log, pit};
+use alloc::collections::BinaryHeap;
+use core::cmp::Ordering as CmpOrdering;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::mutex::SpinMutex;
+use spin::RwLock;
+
+// Ticks per second
+pub const TIMER_FREQUENCY: u64 = 100;
+
+const CALIBRATION_MILLISECONDS: u64 = 100;
+
+static TICK: AtomicU64 = AtomicU64::new(0);
+static LAPIC_TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+// Timeouts dropped because the event queue was full. Logged outside of the interrupt.
+static LOST_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+static TIMER_MANAGER: OnceCell<SpinMutex<TimerManager>> = OnceCell::uninit();
+static TICK_SOURCE: RwLock<Option<TickSource>> = RwLock::new(None);
+// Timers come and go often, and fired one-shot timers are freed in the interrupt handler
+static TIMER_CACHE: ObjectCache<Timer> = ObjectCache::new("timer");
+
+// HPET comparator used for ticks. Timer 0 is always capable of periodic mode.
+const HPET_TICK_TIMER: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickSource {
+    LocalApic,
+    Hpet,
+}
+
+/// The clock source delivering ticks, once init succeeded.
+pub fn tick_source() -> Option<TickSource> {
+    *TICK_SOURCE.read()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+pub type TimerCallback = fn(&Timeout);
+
+/// Delivered through the event queue when a timer expires.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout {
+    pub id: TimerId,
+    pub value: i32,
+    callback: Option<TimerCallback>,
+}
+
+impl Timeout {
+    /// Runs the callback of the timer, if any, outside of interrupt context.
+    pub fn dispatch(&self) {
+        if let Some(callback) = self.callback {
+            callback(self);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Timer {
+    deadline: u64,
+    period: Option<u64>,
+    timeout: Timeout,
+}
+
+// BinaryHeap is a max-heap, so the earliest deadline has to compare as the greatest.
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other
+            .deadline
+            .cmp(&self.deadline)
+            .then_with(|| other.timeout.id.cmp(&self.timeout.id))
+    }
+}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Timer {}
+
+pub struct TimerManager {
+    timers: BinaryHeap<CachedBox<Timer>>,
+    next_id: u64,
+}
+
+impl TimerManager {
+    fn new() -> Self {
+        Self {
+            timers: BinaryHeap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn add(
+        &mut self,
+        deadline: u64,
+        period: Option<u64>,
+        value: i32,
+        callback: Option<TimerCallback>,
+    ) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        let timer = TIMER_CACHE
+            .alloc(Timer {
+                deadline,
+                period,
+                timeout: Timeout {
+                    id,
+                    value,
+                    callback,
+                },
+            })
+            .expect("Failed to allocate a timer");
+        self.timers.push(timer);
+        id
+    }
+
+    fn cancel(&mut self, id: TimerId) -> bool {
+        let count = self.timers.len();
+        let timers = core::mem::take(&mut self.timers);
+        self.timers = timers
+            .into_vec()
+            .into_iter()
+            .filter(|timer| timer.timeout.id != id)
+            .collect();
+        self.timers.len() != count
+    }
+
+    // Called from the timer interrupt. Periodic timers are pushed back right after
+    // being popped, so the heap never has to grow here. Fired one-shot timers go
+    // back to TIMER_CACHE, which does not wait for memory_manager.
+    fn tick(&mut self, now: u64) {
+        while let Some(timer) = self.timers.peek() {
+            if timer.deadline > now {
+                break;
+            }
+            let mut timer = self.timers.pop().unwrap();
+            if event_queue()
+                .push(QueueEvent {
+                    event_type: QueueEventType::TimerTimeout(timer.timeout),
+                })
+                .is_err()
+            {
+                LOST_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+            }
+            if let Some(period) = timer.period {
+                timer.deadline = u64::max(timer.deadline + period, now + 1);
+                self.timers.push(timer);
+            }
+        }
+    }
+}
+
+// TIMER_MANAGER is also locked by the timer interrupt handler.
+fn with_timer_manager<R>(f: impl FnOnce(&mut TimerManager) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut TIMER_MANAGER.get().lock()))
+}
+
+pub fn ticks_from_milliseconds(milliseconds: u64) -> u64 {
+    (milliseconds * TIMER_FREQUENCY + 999) / 1000
+}
+
+/// Fires once after `ticks` ticks.
+pub fn add_oneshot_timer(ticks: u64, value: i32, callback: Option<TimerCallback>) -> TimerId {
+    with_timer_manager(|manager| manager.add(current_tick() + ticks, None, value, callback))
+}
+
+/// Fires every `period` ticks until it is cancelled.
+pub fn add_periodic_timer(period: u64, value: i32, callback: Option<TimerCallback>) -> TimerId {
+    let period = period.max(1);
+    with_timer_manager(|manager| {
+        manager.add(current_tick() + period, Some(period), value, callback)
+    })
+}
+
+/// Returns false if the timer has already fired (one-shot) or does not exist.
+pub fn cancel_timer(id: TimerId) -> bool {
+    with_timer_manager(|manager| manager.cancel(id))
+}
+
+/// Number of ticks since the timer was started. It never goes backwards.
+pub fn current_tick() -> u64 {
+    TICK.load(Ordering::Relaxed)
+}
+
+/// Logs the timeouts lost since the last call. Not for interrupt context.
+pub fn report_lost_timeouts() {
+    let lost = LOST_TIMEOUTS.swap(0, Ordering::Relaxed);
+    if lost != 0 {
+        log!(
+            LogLevel::Warn,
+            "timer: event queue is full, {} timeouts are lost\n",
+            lost
+        );
+    }
+}
+
+/// Counters of the cache the timers are allocated from.
+pub fn cache_stats() -> SlabStats {
+    TIMER_CACHE.stats()
+}
+
+/// Local APIC timer counts per second, measured at boot.
+pub fn lapic_timer_frequency() -> u64 {
+    LAPIC_TIMER_FREQUENCY.load(Ordering::Relaxed)
+}
+
+fn on_tick() {
+    let now = TICK.fetch_add(1, Ordering::Relaxed) + 1;
+    TIMER_MANAGER.get().lock().tick(now);
+    // A dropped tick event is harmless as the counter has already advanced
+    let _ = event_queue().push(QueueEvent {
+        event_type: QueueEventType::TimerTick,
+    });
+}
+
+/// Busy-waits on the ACPI PM timer, or on the PIT when the firmware has none.
+pub fn wait_reference_milliseconds(milliseconds: u64) {
+    let waited = acpi::tables().map_or(Err(()), |tables| {
+        tables.pm_timer_wait_milliseconds(milliseconds)
+    });
+    if waited.is_err() {
+        pit::wait_milliseconds(milliseconds);
+    }
+}
+
+fn calibrate_lapic_timer() -> u64 {
+    let lapic = local_apic();
+    lapic.set_timer_divide(TimerDivide::By1);
+    lapic.write_lvt(
+        Lvt::Timer,
+        LvtEntry::new()
+            .with_masked(true)
+            .with_timer_mode(TimerMode::OneShot),
+    );
+    lapic.set_timer_initial_count(u32::MAX);
+    wait_reference_milliseconds(CALIBRATION_MILLISECONDS);
+    let elapsed = u64::from(u32::MAX - lapic.timer_current_count());
+    lapic.set_timer_initial_count(0);
+
+    elapsed * 1000 / CALIBRATION_MILLISECONDS
+}
+
+fn start_lapic_timer(vector: u8) -> Result<(), ()> {
+    let frequency = calibrate_lapic_timer();
+    LAPIC_TIMER_FREQUENCY.store(frequency, Ordering::Relaxed);
+    log!(LogLevel::Info, "Local APIC timer: {} Hz\n", frequency);
+    if frequency < TIMER_FREQUENCY {
+        return Err(());
+    }
+
+    let lapic = local_apic();
+    lapic.write_lvt(
+        Lvt::Timer,
+        LvtEntry::new()
+            .with_vector(vector)
+            .with_masked(false)
+            .with_timer_mode(TimerMode::Periodic),
+    );
+    lapic.set_timer_initial_count((frequency / TIMER_FREQUENCY) as u32);
+
+    Ok(())
+}
+
+fn start_hpet_timer(vector: u8) -> Result<(), ()> {
+    let hpet = hpet::hpet().ok_or(())?;
+    let period = hpet.frequency() / TIMER_FREQUENCY;
+    hpet.start_timer(HPET_TICK_TIMER, HpetTimerMode::Periodic, period, vector)
+}
+
+/// Starts delivering ticks from `source`, falling back to the other source if it fails.
+pub fn init(source: TickSource) -> Result<(), ()> {
+    TIMER_MANAGER.init_once(|| SpinMutex::new(TimerManager::new()));
+    let vector = interrupt::request_interrupt(on_tick)?;
+
+    let fallback = match source {
+        TickSource::LocalApic => TickSource::Hpet,
+        TickSource::Hpet => TickSource::LocalApic,
+    };
+    for source in [source, fallback] {
+        let result = match source {
+            TickSource::LocalApic => start_lapic_timer(vector),
+            TickSource::Hpet => start_hpet_timer(vector),
+        };
+        if result.is_ok() {
+            log!(LogLevel::Info, "Tick source: {:?}\n", source);
+            *TICK_SOURCE.write() = Some(source);
+            return Ok(());
+        }
+        log!(LogLevel::Warn, "Failed to start tick source {:?}\n", source);
+    }
+
+    interrupt::free_vector(vector);
+    Err(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn deadlines(manager: &TimerManager) -> Vec<u64> {
+        let mut deadlines: Vec<u64> = manager.timers.iter().map(|t| t.deadline).collect();
+        deadlines.sort_unstable();
+        deadlines
+    }
+
+    #[test_case]
+    fn earliest_deadline_comes_first() {
+        let mut manager = TimerManager::new();
+        manager.add(30, None, 0, None);
+        let earliest = manager.add(10, None, 0, None);
+        manager.add(20, None, 0, None);
+        assert_eq!(manager.timers.peek().map(|t| t.timeout.id), Some(earliest));
+    }
+
+    #[test_case]
+    fn cancel_removes_only_that_timer() {
+        let mut manager = TimerManager::new();
+        let first = manager.add(10, None, 0, None);
+        let second = manager.add(20, Some(20), 0, None);
+        assert!(manager.cancel(first));
+        assert!(!manager.cancel(first));
+        assert_eq!(manager.timers.peek().map(|t| t.timeout.id), Some(second));
+        assert!(manager.cancel(second));
+        assert!(manager.timers.is_empty());
+    }
+
+    #[test_case]
+    fn tick_fires_due_timers_and_reschedules_periodic_ones() {
+        let mut manager = TimerManager::new();
+        manager.add(5, None, 0, None);
+        manager.add(5, Some(10), 0, None);
+        manager.add(50, None, 0, None);
+        manager.tick(4);
+        assert_eq!(deadlines(&manager), [5, 5, 50]);
+        manager.tick(5);
+        assert_eq!(deadlines(&manager), [15, 50]);
+        // A periodic timer which fell behind fires once, not once per missed period
+        manager.tick(40);
+        assert_eq!(deadlines(&manager), [41, 50]);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::logger::Level as LogLevel;
+use crate::{log, timer};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+const CALIBRATION_MILLISECONDS: u64 = 50;
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+// Advanced Power Management Information
+const CPUID_APM_LEAF: u32 = 0x8000_0007;
+const CPUID_APM_INVARIANT_TSC: u32 = 1 << 8;
+
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the TSC runs at a constant rate regardless of power states.
+pub fn is_invariant() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf < CPUID_APM_LEAF {
+        return false;
+    }
+    unsafe { __cpuid(CPUID_APM_LEAF) }.edx & CPUID_APM_INVARIANT_TSC != 0
+}
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// TSC ticks per second, or 0 before init.
+pub fn frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::Relaxed)
+}
+
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    let frequency = frequency();
+    if frequency == 0 {
+        return 0;
+    }
+    (u128::from(ticks) * NANOS_PER_SECOND / u128::from(frequency)) as u64
+}
+
+fn nanos_to_ticks(nanos: u64) -> u64 {
+    (u128::from(nanos) * u128::from(frequency()) / NANOS_PER_SECOND) as u64
+}
+
+/// Nanoseconds since init.
+pub fn now_nanos() -> u64 {
+    ticks_to_nanos(read().saturating_sub(BOOT_TSC.load(Ordering::Relaxed)))
+}
+
+/// Time since init.
+pub fn now() -> Duration {
+    Duration::from_nanos(now_nanos())
+}
+
+/// A point in time measured with the TSC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(read())
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(ticks_to_nanos(self.0.saturating_sub(earlier.0)))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0 + nanos_to_ticks(rhs.as_nanos() as u64))
+    }
+}
+
+pub fn init() {
+    if !is_invariant() {
+        log!(
+            LogLevel::Warn,
+            "TSC is not invariant; timestamps may drift with power states\n"
+        );
+    }
+
+    let start = read();
+    timer::wait_reference_milliseconds(CALIBRATION_MILLISECONDS);
+    let end = read();
+    let frequency = (end - start) * 1000 / CALIBRATION_MILLISECONDS;
+
+    BOOT_TSC.store(start, Ordering::Relaxed);
+    TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+    log!(LogLevel::Info, "TSC: {} Hz\n", frequency);
+}
@@ -1,13 +1,26 @@
+use crate::driver::{PciDeviceId, PciDriver};
 use crate::logger::Level as LogLevel;
-use crate::pci::{Device, Devices, MsiDeliveryMode, MsiTriggerMode};
+use crate::pci::{Device, MsiDeliveryMode, MsiTriggerMode, PowerState};
 use crate::queue::{event_queue, QueueEvent, QueueEventType};
 use crate::sync::once_cell::OnceCell;
-use crate::{log, mouse, pci};
-use core::option::Option::{None, Some};
+use crate::{apic, driver, interrupt, log, mouse, pci};
 use mikanos_usb_driver::{HidMouseDriver, XhciController};
 use spin::mutex::SpinMutex;
-use volatile::Volatile;
-use x86_64::structures::idt::InterruptStackFrame;
+
+const INTEL_VENDOR_ID: u16 = 0x8086;
+
+// Intel controllers are preferred when there are several
+static ID_TABLE: [PciDeviceId; 2] = [
+    PciDeviceId::class(0x0c, 0x03, 0x30).with_vendor(INTEL_VENDOR_ID),
+    PciDeviceId::class(0x0c, 0x03, 0x30),
+];
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "xhci",
+    id_table: &ID_TABLE,
+    probe,
+    remove: None,
+};
 
 static XHC: OnceCell<SpinMutex<&'static mut XhciController>> = OnceCell::uninit();
 
@@ -15,58 +28,49 @@ pub fn xhc() -> &'static SpinMutex<&'static mut XhciController> {
     XHC.get()
 }
 
-fn notify_end_of_interrupt() {
-    let mut memory = Volatile::new(unsafe { (0xfee000b0 as *mut u32).as_mut().unwrap() });
-    memory.write(0);
-}
-
-pub extern "x86-interrupt" fn xhc_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn xhc_interrupt_handler() {
     event_queue()
         .push(QueueEvent {
             event_type: QueueEventType::InterruptXHCI,
         })
         .unwrap();
-    notify_end_of_interrupt();
 }
 
-pub fn init(devices: &Devices) -> Result<(), ()> {
-    let mut xhc_device: Option<&Device> = None;
-    for device in devices {
-        log!(LogLevel::Info, "{}\n", device);
-        if ((device.class_code >> 24) & 0xff) as u8 == 0x0c
-            && ((device.class_code >> 16) & 0xff) as u8 == 0x03
-            && ((device.class_code >> 8) & 0xff) as u8 == 0x30
-        {
-            xhc_device = Some(device);
-            if device.vendor_id == 0x8086 {
-                break;
-            }
-        }
+fn probe(xhc_device: &Device) -> Result<(), ()> {
+    // Only a single controller is supported
+    if XHC.try_get().is_ok() {
+        return Err(());
     }
-
-    xhc_device.expect("XHC Device is not found");
-    let xhc_device = xhc_device.unwrap();
     log!(LogLevel::Info, "xHC has been found: {}\n", xhc_device);
+    // Firmware may have left the controller suspended. Not every device has the capability.
+    let _ = xhc_device.set_power_state(PowerState::D0);
 
-    // MSI Config
-    let bsp_local_apic_id = unsafe { *(0xfee00020 as *const u32) } >> 24;
-    pci::configure_msi_fixed_destination(
+    let xhc_bar = xhc_device.bar(0)?;
+    log!(LogLevel::Info, "xHC BAR0: {}\n", xhc_bar);
+    let xhc_mmio_base = xhc_bar.map()?.as_u64();
+    log!(LogLevel::Info, "xHC mmio_base = {:08x}\n", xhc_mmio_base);
+
+    // MSI Config. The vector is requested last so that no earlier failure leaks it.
+    let bsp_local_apic_id = apic::local_apic().id();
+    let vector = interrupt::request_interrupt(xhc_interrupt_handler)?;
+    log!(LogLevel::Info, "xHC interrupt vector = {:#04x}\n", vector);
+    if pci::configure_msi_fixed_destination(
         xhc_device,
         bsp_local_apic_id,
         MsiTriggerMode::Level,
         MsiDeliveryMode::Fixed,
-        0x40,
+        vector,
         0,
-    )?;
-
-    let xhc_bar = xhc_device.read_bar(0).expect("Read bar error");
-    log!(LogLevel::Info, "xHC BAR0 = {:08x}\n", xhc_bar);
-    let xhc_mmio_base = xhc_bar & !0xf;
-    log!(LogLevel::Info, "xHC mmio_base = {:08x}\n", xhc_mmio_base);
+    )
+    .is_err()
+    {
+        interrupt::free_vector(vector);
+        return Err(());
+    }
 
     let xhc = unsafe { XhciController::new(xhc_mmio_base) };
-    if xhc_device.vendor_id == 0x8086 {
-        pci::switch_ehci_to_xhci(&xhc_device, &devices);
+    if xhc_device.vendor_id == INTEL_VENDOR_ID {
+        pci::switch_ehci_to_xhci(xhc_device, driver::devices());
     }
     xhc.init();
     log!(LogLevel::Info, "xHC init\n");
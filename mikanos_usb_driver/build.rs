@@ -31,6 +31,7 @@ fn build_lib() -> Result<()> {
         .flag("-nostdlibinc")
         .flag("-ffreestanding")
         .flag("-mno-red-zone")
+        .flag("-fno-omit-frame-pointer")
         .flag("-fno-exceptions")
         .flag("-fno-rtti")
         .flag("-std=c++17")
@@ -10,6 +10,8 @@ extern "C" {
 #endif
 
 int32_t mikanos_log(int32_t level, const char *msg, size_t msg_len);
+// Nanoseconds since the kernel calibrated its clock
+uint64_t mikanos_now_ns(void);
 
 #ifdef __cplusplus
 }
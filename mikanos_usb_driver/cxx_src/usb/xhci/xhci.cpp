@@ -1,5 +1,6 @@
 #include "usb/xhci/xhci.hpp"
 
+#include "cxx_support.h"
 #include "logger.hpp"
 #include "usb/setupdata.hpp"
 #include "usb/device.hpp"
@@ -42,6 +43,11 @@ namespace {
    */
   uint8_t addressing_port{0};
 
+  /** ポートのリセットを開始した時刻（mikanos_now_ns）．
+   * 設定完了までにかかった時間を記録するために使う．
+   */
+  std::array<uint64_t, 256> port_reset_start_ns{};  // index: port number
+
   void InitializeSlotContext(SlotContext& ctx, Port& port) {
     ctx.bits.route_string = 0;
     ctx.bits.root_hub_port_num = port.Number();
@@ -104,6 +110,7 @@ namespace {
       }
       addressing_port = port.Number();
       port_config_phase[port.Number()] = ConfigPhase::kResettingPort;
+      port_reset_start_ns[port.Number()] = mikanos_now_ns();
       port.Reset();
     }
     return MAKE_ERROR(Error::kSuccess);
@@ -188,6 +195,9 @@ namespace {
     dev->OnEndpointsConfigured();
 
     port_config_phase[port_id] = ConfigPhase::kConfigured;
+    Log(kInfo, "USB port %d configured in %llu us\n", port_id,
+        static_cast<unsigned long long>(
+            (mikanos_now_ns() - port_reset_start_ns[port_id]) / 1000));
     return MAKE_ERROR(Error::kSuccess);
   }
 